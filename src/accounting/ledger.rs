@@ -0,0 +1,249 @@
+use crate::chat_gpt_api::specification::{Message, Model, Usage};
+use crate::chat_gpt_api::tokens::estimate_usage;
+use crate::session::session_key;
+use crate::turn::Completion;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Price of a model in USD per 1K tokens.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub(crate) struct Price {
+    pub(crate) prompt: f64,
+    pub(crate) completion: f64,
+}
+
+impl Price {
+    fn cost(&self, usage: &Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt
+            + usage.completion_tokens as f64 * self.completion)
+            / 1000.0
+    }
+}
+
+/// Prices of the models at the time of writing, which can be overridden by the server config.
+pub(crate) fn default_prices() -> HashMap<String, Price> {
+    [
+        (Model::Gpt35Turbo, 0.0015, 0.002),
+        (Model::Gpt35Turbo0613, 0.0015, 0.002),
+        (Model::Gpt35Turbo16k, 0.003, 0.004),
+        (Model::Gpt35Turbo16k0613, 0.003, 0.004),
+        (Model::Gpt4, 0.03, 0.06),
+        (Model::Gpt40613, 0.03, 0.06),
+        (Model::Gpt432k, 0.06, 0.12),
+        (Model::Gpt432k0613, 0.06, 0.12),
+    ]
+    .into_iter()
+    .map(|(model, prompt, completion)| {
+        (
+            model.parse_to_string().unwrap(),
+            Price { prompt, completion },
+        )
+    })
+    .collect()
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Totals {
+    pub(crate) requests: u64,
+    pub(crate) estimated_requests: u64,
+    pub(crate) prompt_tokens: u64,
+    pub(crate) completion_tokens: u64,
+    pub(crate) total_tokens: u64,
+    pub(crate) cost: f64,
+}
+
+impl Totals {
+    fn add(&mut self, usage: &Usage, estimated: bool, cost: f64) {
+        self.requests += 1;
+        if estimated {
+            self.estimated_requests += 1;
+        }
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.total_tokens += usage.total_tokens;
+        self.cost += cost;
+    }
+}
+
+/// Estimated usage of a streaming completion,
+/// including the part generated before an interruption.
+pub(crate) fn completion_usage(messages: &[Message], completion: &Completion) -> Option<Usage> {
+    match completion {
+        Completion::Finished(streamed) => Some(streamed.usage.clone()),
+        Completion::Interrupted(delivered) => Some(estimate_usage(messages, delivered)),
+        // Billed only if a part of the answer has been sent before the error
+        Completion::Failed(sent) if sent.iter().any(|content| !content.is_empty()) => {
+            Some(estimate_usage(messages, sent))
+        }
+        Completion::Failed(_) => None,
+    }
+}
+
+/// Token usage and cost accumulated per session of the client, per client and per model.
+#[derive(Default)]
+pub(crate) struct Ledger {
+    prices: HashMap<String, Price>,
+    pub(crate) total: Totals,
+    pub(crate) sessions: HashMap<String, Totals>,
+    pub(crate) clients: HashMap<String, Totals>,
+    pub(crate) models: HashMap<String, Totals>,
+}
+
+impl Ledger {
+    /// Creates a ledger with the default prices overridden by the prices.
+    pub(crate) fn new(prices: HashMap<String, Price>) -> Self {
+        let mut all_prices = default_prices();
+        all_prices.extend(prices);
+
+        Self {
+            prices: all_prices,
+            ..Default::default()
+        }
+    }
+
+    /// Records the usage of a completion and returns the cost, which is zero for an unknown model.
+    pub(crate) fn record(
+        &mut self,
+        session_id: &str,
+        client: &str,
+        model: &str,
+        usage: &Usage,
+        estimated: bool,
+    ) -> f64 {
+        let cost = self
+            .prices
+            .get(model)
+            .map_or(0.0, |price| price.cost(usage));
+
+        self.total.add(usage, estimated, cost);
+        for (totals, key) in [
+            (&mut self.sessions, session_key(session_id, client)),
+            (&mut self.clients, client.to_string()),
+            (&mut self.models, model.to_string()),
+        ] {
+            totals.entry(key).or_default().add(usage, estimated, cost);
+        }
+
+        cost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_gpt_api::client::StreamedCompletion;
+    use crate::chat_gpt_api::specification::Role;
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn cost_is_priced_per_1k_tokens() {
+        let price = Price {
+            prompt: 0.0015,
+            completion: 0.002,
+        };
+
+        assert_close(price.cost(&usage(1000, 500)), 0.0025);
+        assert_close(price.cost(&usage(0, 0)), 0.0);
+    }
+
+    #[test]
+    fn configured_prices_override_the_defaults() {
+        let prices = HashMap::from([(
+            "gpt-4".to_string(),
+            Price {
+                prompt: 1.0,
+                completion: 2.0,
+            },
+        )]);
+        let mut ledger = Ledger::new(prices);
+
+        assert_close(
+            ledger.record("session", "alice", "gpt-4", &usage(1000, 1000), false),
+            3.0,
+        );
+        assert_close(
+            ledger.record(
+                "session",
+                "alice",
+                "gpt-3.5-turbo",
+                &usage(1000, 1000),
+                false,
+            ),
+            0.0035,
+        );
+    }
+
+    #[test]
+    fn usage_is_recorded_per_session_client_and_model() {
+        let mut ledger = Ledger::new(HashMap::new());
+
+        ledger.record("session", "alice", "gpt-4", &usage(100, 50), false);
+        ledger.record("session", "alice", "gpt-4", &usage(10, 5), true);
+        ledger.record("session", "bob", "gpt-3.5-turbo", &usage(1, 1), false);
+
+        assert_eq!(ledger.total.requests, 3);
+        assert_eq!(ledger.total.estimated_requests, 1);
+        assert_eq!(ledger.total.total_tokens, 167);
+
+        // The clients sharing the session ID are counted separately
+        let alice = &ledger.sessions["alice:session"];
+        assert_eq!(alice.requests, 2);
+        assert_eq!(alice.prompt_tokens, 110);
+        assert_eq!(alice.completion_tokens, 55);
+        assert_close(alice.cost, (110.0 * 0.03 + 55.0 * 0.06) / 1000.0);
+        assert_eq!(ledger.sessions["bob:session"].requests, 1);
+
+        assert_eq!(ledger.clients["alice"].total_tokens, 165);
+        assert_eq!(ledger.models["gpt-3.5-turbo"].total_tokens, 2);
+    }
+
+    #[test]
+    fn unknown_model_costs_nothing() {
+        let mut ledger = Ledger::new(HashMap::new());
+
+        let cost = ledger.record("session", "alice", "unknown", &usage(1000, 1000), false);
+
+        assert_close(cost, 0.0);
+        assert_eq!(ledger.models["unknown"].total_tokens, 2000);
+    }
+
+    #[test]
+    fn usage_of_completions_is_reported_or_estimated() {
+        let messages = vec![Message::new(Role::User, "Hello")];
+        let finished = Completion::Finished(StreamedCompletion {
+            usage: usage(7, 3),
+            ..Default::default()
+        });
+        let interrupted = Completion::Interrupted(vec!["Hi!".to_string()]);
+
+        let reported = completion_usage(&messages, &finished).unwrap();
+        assert_eq!(reported.total_tokens, 10);
+
+        let estimated = completion_usage(&messages, &interrupted).unwrap();
+        assert_eq!(estimated.prompt_tokens, 9);
+        assert_eq!(estimated.completion_tokens, 1);
+
+        let failed = Completion::Failed(vec!["Hi!".to_string()]);
+        assert_eq!(
+            completion_usage(&messages, &failed).unwrap().total_tokens,
+            10
+        );
+        assert!(completion_usage(&messages, &Completion::Failed(Vec::new())).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+/// Messages, client and server generated from accounting.proto.
+pub mod accounting_rpc {
+    // Documented by the comments of the proto
+    #![allow(missing_docs)]
+    tonic::include_proto!("accounting");
+
+    pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("accounting_descriptor");
+}
+
+use crate::accounting::ledger::Totals;
+use crate::api_state::ApiState;
+use crate::identity::{require_role, Role};
+use accounting_rpc::accounting_server::Accounting;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+
+pub struct MyAccounting {
+    pub(crate) state: Arc<Mutex<ApiState>>,
+}
+
+#[tonic::async_trait]
+impl Accounting for MyAccounting {
+    // grpcurl -plaintext -d '{}' localhost:8000 accounting.Accounting/GetUsage
+    async fn get_usage(
+        &self,
+        request: Request<accounting_rpc::UsageRequest>,
+    ) -> Result<Response<accounting_rpc::UsageReport>, Status> {
+        require_role(&request, Role::Operator)?;
+
+        let state = self.state.lock().await;
+        let ledger = &state.ledger;
+
+        Ok(Response::new(accounting_rpc::UsageReport {
+            total: Some(to_rpc(&ledger.total)),
+            sessions: to_rpc_map(&ledger.sessions),
+            clients: to_rpc_map(&ledger.clients),
+            models: to_rpc_map(&ledger.models),
+        }))
+    }
+}
+
+fn to_rpc(totals: &Totals) -> accounting_rpc::UsageTotals {
+    accounting_rpc::UsageTotals {
+        requests: totals.requests,
+        estimated_requests: totals.estimated_requests,
+        prompt_tokens: totals.prompt_tokens,
+        completion_tokens: totals.completion_tokens,
+        total_tokens: totals.total_tokens,
+        cost: totals.cost,
+    }
+}
+
+fn to_rpc_map(totals: &HashMap<String, Totals>) -> HashMap<String, accounting_rpc::UsageTotals> {
+    totals
+        .iter()
+        .map(|(key, totals)| (key.clone(), to_rpc(totals)))
+        .collect()
+}
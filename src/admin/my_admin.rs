@@ -0,0 +1,145 @@
+/// Messages, client and server generated from admin.proto.
+pub mod admin_rpc {
+    // Documented by the comments of the proto
+    #![allow(missing_docs)]
+    tonic::include_proto!("admin");
+
+    pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("admin_descriptor");
+}
+
+use crate::api_state::ApiState;
+use crate::chat_gpt_api::memory::Memory;
+use crate::chat_gpt_api::specification::Model;
+use crate::identity::{client_identity, require_role, Role};
+use crate::redaction::redact;
+use admin_rpc::admin_server::Admin;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+
+pub struct MyAdmin {
+    pub(crate) state: Arc<Mutex<ApiState>>,
+}
+
+#[tonic::async_trait]
+impl Admin for MyAdmin {
+    // grpcurl -plaintext -d '{}' localhost:8000 admin.Admin/GetSettings
+    async fn get_settings(
+        &self,
+        request: Request<admin_rpc::GetSettingsRequest>,
+    ) -> Result<Response<admin_rpc::Settings>, Status> {
+        require_role(&request, Role::Operator)?;
+
+        let state = self.state.lock().await;
+
+        Ok(Response::new(settings(&state)))
+    }
+
+    // grpcurl -plaintext -d '{ "model": "gpt-4" }' localhost:8000 admin.Admin/SetModel
+    async fn set_model(
+        &self,
+        request: Request<admin_rpc::ModelSetting>,
+    ) -> Result<Response<admin_rpc::Settings>, Status> {
+        require_role(&request, Role::Admin)?;
+
+        tracing::info!(
+            client = %client_identity(&request),
+            model = %request.get_ref().model,
+            "Got a request to set model"
+        );
+
+        let setting = request.into_inner();
+        let model = Model::parse_to_model(&setting.model).map_err(|_| {
+            Status::new(
+                tonic::Code::InvalidArgument,
+                format!("Invalid model: {}", setting.model),
+            )
+        })?;
+
+        let mut state = self.state.lock().await;
+        state.model = model;
+
+        Ok(Response::new(settings(&state)))
+    }
+
+    // grpcurl -plaintext -d '{ "prompt": "You are a helpful assistant." }' localhost:8000 admin.Admin/SetPrompt
+    async fn set_prompt(
+        &self,
+        request: Request<admin_rpc::PromptSetting>,
+    ) -> Result<Response<admin_rpc::Settings>, Status> {
+        require_role(&request, Role::Admin)?;
+
+        tracing::info!(
+            client = %client_identity(&request),
+            prompt = %redact(&request.get_ref().prompt),
+            "Got a request to set prompt"
+        );
+
+        let mut state = self.state.lock().await;
+        state.prompt = request.into_inner().prompt;
+
+        Ok(Response::new(settings(&state)))
+    }
+
+    // grpcurl -plaintext -d '{}' localhost:8000 admin.Admin/ListSessions
+    async fn list_sessions(
+        &self,
+        request: Request<admin_rpc::ListSessionsRequest>,
+    ) -> Result<Response<admin_rpc::SessionList>, Status> {
+        require_role(&request, Role::Operator)?;
+
+        let state = self.state.lock().await;
+
+        let mut session_ids: Vec<String> = state.sessions.keys().cloned().collect();
+        session_ids.sort();
+
+        Ok(Response::new(admin_rpc::SessionList { session_ids }))
+    }
+
+    // grpcurl -plaintext -d '{ "session_id": "alice" }' localhost:8000 admin.Admin/DumpMemory
+    async fn dump_memory(
+        &self,
+        request: Request<admin_rpc::DumpMemoryRequest>,
+    ) -> Result<Response<admin_rpc::MemoryDump>, Status> {
+        require_role(&request, Role::Admin)?;
+
+        let state = self.state.lock().await;
+
+        let session_id = request.into_inner().session_id;
+        let Some(session) = state.sessions.get(&session_id) else {
+            return Err(Status::new(
+                tonic::Code::NotFound,
+                format!("Session not found: {}", session_id),
+            ));
+        };
+
+        let messages = session
+            .context_memory
+            .get()
+            .into_iter()
+            .map(|message| {
+                let (function_name, function_arguments) = match message.function_call {
+                    None => (String::new(), String::new()),
+                    Some(function_call) => (function_call.name, function_call.arguments),
+                };
+                admin_rpc::Message {
+                    role: message.role,
+                    content: message.content.unwrap_or_default(),
+                    function_name,
+                    function_arguments,
+                }
+            })
+            .collect();
+
+        Ok(Response::new(admin_rpc::MemoryDump { messages }))
+    }
+}
+
+fn settings(state: &ApiState) -> admin_rpc::Settings {
+    admin_rpc::Settings {
+        model: state.model.parse_to_string().unwrap(),
+        prompt: state.prompt.clone(),
+        memory_size: state.memory_size as u64,
+    }
+}
@@ -1,8 +1,152 @@
-use crate::chat_gpt_api::memory::FiniteQueueMemory;
-use crate::chat_gpt_api::specification::Model;
+use crate::accounting::ledger::{completion_usage, Ledger};
+use crate::chat_gpt_api::client::Upstream;
+use crate::chat_gpt_api::memory::Memory;
+use crate::chat_gpt_api::specification::{Message, Model, Options, Role, Sampling, Usage};
+use crate::identity::ClientIdentity;
+use crate::quota::Quotas;
+use crate::session::Session;
+use crate::speak::character::{CharacterProfile, DEFAULT_CHARACTER_NAME};
+use crate::turn::{BargeInPolicy, Completion};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tonic::Status;
+
+/// Prompt preceding the context memory in the messages of a completion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Prompt {
+    /// System prompt of the server.
+    Chat,
+    /// Persona and examples of the character selected in the session.
+    Character,
+}
 
 pub(crate) struct ApiState {
+    pub(crate) upstream: Arc<Upstream>,
     pub(crate) model: Model,
     pub(crate) prompt: String,
-    pub(crate) context_memory: FiniteQueueMemory,
+    pub(crate) memory_size: usize,
+    pub(crate) sessions: HashMap<String, Session>,
+    pub(crate) characters: HashMap<String, CharacterProfile>,
+    pub(crate) barge_in: BargeInPolicy,
+    pub(crate) max_continuations: u32,
+    /// Sampling of the server, overridden by the character of the session.
+    pub(crate) sampling: Sampling,
+    pub(crate) ledger: Ledger,
+    pub(crate) quotas: Quotas,
+}
+
+impl ApiState {
+    /// Gets the session of the ID, creating a new one if it does not exist yet.
+    pub(crate) fn session(&mut self, session_id: &str) -> &mut Session {
+        let memory_size = self.memory_size;
+        self.sessions
+            .entry(session_id.to_string())
+            .or_insert_with(|| Session::new(memory_size, DEFAULT_CHARACTER_NAME.to_string()))
+    }
+
+    /// Gets the session of the ID for the client, binding a new session to the client.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn authorize_session(
+        &mut self,
+        session_id: &str,
+        client: &ClientIdentity,
+    ) -> Result<&mut Session, Status> {
+        let session = self.session(session_id);
+        session.authorize(client)?;
+        Ok(session)
+    }
+
+    /// Gets the character profile selected in the session.
+    pub(crate) fn character(&mut self, session_id: &str) -> CharacterProfile {
+        let name = self.session(session_id).character.clone();
+        match self.characters.get(&name) {
+            Some(profile) => profile.clone(),
+            None => CharacterProfile::default(),
+        }
+    }
+
+    /// Options of a completion in the session seeded with the model and the sampling of the server,
+    /// of which the messages are the prompt followed by the context memory.
+    ///
+    /// The character selected in the session overrides the sampling of the server.
+    pub(crate) fn options(&mut self, session_id: &str, prompt: Prompt) -> Options {
+        let character = self.character(session_id);
+        let sampling = character.sampling.or(self.sampling);
+        let mut messages = match prompt {
+            Prompt::Chat => vec![Message::new(Role::System, self.prompt.clone())],
+            Prompt::Character => character.prompt_messages(),
+        };
+        messages.extend(self.session(session_id).context_memory.get());
+
+        sampling.apply(Options::new(
+            self.model.parse_to_string().unwrap(),
+            messages,
+        ))
+    }
+
+    /// Checks the quotas of the client and the session before forwarding a request to the API.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn check_quota(&mut self, session_id: &str, client: &str) -> Result<(), Status> {
+        self.quotas.check(session_id, client, Utc::now())
+    }
+
+    /// Records the usage of a completion in the ledger and consumes the quotas.
+    pub(crate) fn record_usage(
+        &mut self,
+        session_id: &str,
+        client: &str,
+        model: &str,
+        usage: &Usage,
+        estimated: bool,
+    ) {
+        let cost = self
+            .ledger
+            .record(session_id, client, model, usage, estimated);
+        self.quotas
+            .consume(session_id, client, usage.total_tokens, cost, Utc::now());
+    }
+
+    /// Records the estimated usage of a streaming completion.
+    pub(crate) fn record_completion(
+        &mut self,
+        session_id: &str,
+        client: &str,
+        model: &str,
+        messages: &[Message],
+        completion: &Completion,
+    ) {
+        if let Some(usage) = completion_usage(messages, completion) {
+            self.record_usage(session_id, client, model, &usage, true);
+        }
+    }
+}
+
+#[cfg(test)]
+impl ApiState {
+    /// State of the unit tests with the mock of the upstream API and no limit.
+    pub(crate) fn with_mock() -> Self {
+        use crate::chat_gpt_api::client::UpstreamConfig;
+        use crate::chat_gpt_api::mock::MockConfig;
+
+        let upstream = Upstream::new(UpstreamConfig {
+            mock: Some(MockConfig::default()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        Self {
+            upstream: Arc::new(upstream),
+            model: Model::Gpt35Turbo,
+            prompt: String::new(),
+            memory_size: 10,
+            sessions: HashMap::new(),
+            characters: HashMap::new(),
+            barge_in: BargeInPolicy::CancelAndReplace,
+            max_continuations: 0,
+            sampling: Default::default(),
+            ledger: Ledger::new(HashMap::new()),
+            quotas: Quotas::new(Default::default(), HashMap::new()),
+        }
+    }
 }
@@ -0,0 +1,434 @@
+use crate::identity::{client_identity, ClientIdentity, Role};
+use anyhow::Result;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::{Code, Request, Status};
+
+/// Authentication configured like:
+///
+/// ```toml
+/// [auth]
+/// jwks_path = "jwks.json"
+/// issuer = "https://auth.example.com"
+/// algorithms = ["RS256"]
+///
+/// [[auth.keys]]
+/// identity = "alice"
+/// sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+/// services = ["chat.Chat"]
+///
+/// [[auth.keys]]
+/// identity = "bob"
+/// sha256 = "fcde2b2edba56bf408601fb721fe9b5c338d10ee429ea04fae5511b68fbf8fb9"
+/// role = "admin"
+/// ```
+///
+/// Authentication is disabled when neither keys nor a JWKS file are configured,
+/// then every client has the anonymous role.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub(crate) struct AuthConfig {
+    pub(crate) keys: Vec<ApiKeyConfig>,
+    /// JWKS file to verify JWTs as bearer tokens.
+    pub(crate) jwks_path: Option<String>,
+    pub(crate) issuer: Option<String>,
+    pub(crate) audience: Option<String>,
+    /// Algorithms allowed for JWTs in addition to the `alg` of the JWK.
+    /// Tokens verified by a JWK without `alg` are rejected unless it is set.
+    pub(crate) algorithms: Vec<Algorithm>,
+    /// Role of the clients while the authentication is disabled.
+    pub(crate) anonymous_role: Role,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ApiKeyConfig {
+    pub(crate) identity: String,
+    /// SHA-256 hash of the key in hex not to keep the key itself in the config.
+    pub(crate) sha256: String,
+    /// Services allowed for the key like "chat.Chat", all services if empty.
+    #[serde(default)]
+    pub(crate) services: Vec<String>,
+    #[serde(default)]
+    pub(crate) role: Role,
+}
+
+/// Claims of a JWT, where the subject is the identity of the client.
+#[derive(Deserialize, Debug)]
+struct Claims {
+    sub: String,
+    /// Services allowed for the token, all services if not set.
+    services: Option<Vec<String>>,
+    role: Option<Role>,
+}
+
+/// Client authenticated by a bearer token.
+struct Principal {
+    identity: String,
+    services: Vec<String>,
+    role: Role,
+}
+
+pub(crate) struct Authenticator {
+    /// Keys by the hash.
+    keys: HashMap<String, ApiKeyConfig>,
+    jwks: Option<JwkSet>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    algorithms: Vec<Algorithm>,
+    anonymous_role: Role,
+}
+
+impl Authenticator {
+    pub(crate) fn new(config: &AuthConfig) -> Result<Self> {
+        let keys = config
+            .keys
+            .iter()
+            .map(|key| (key.sha256.to_lowercase(), key.clone()))
+            .collect();
+
+        let jwks = match &config.jwks_path {
+            None => None,
+            Some(path) => {
+                let text = fs::read_to_string(path)?;
+                let jwks = serde_json::from_str::<JwkSet>(&text)
+                    .map_err(|error| anyhow::anyhow!("Invalid JWKS {:?}: {}", path, error))?;
+                Some(jwks)
+            }
+        };
+
+        Ok(Self {
+            keys,
+            jwks,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            algorithms: config.algorithms.clone(),
+            anonymous_role: config.anonymous_role,
+        })
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.keys.is_empty() || self.jwks.is_some()
+    }
+
+    /// Interceptor to authenticate requests to the service.
+    pub(crate) fn interceptor(self: &Arc<Self>, service: &'static str) -> AuthInterceptor {
+        AuthInterceptor {
+            authenticator: Arc::clone(self),
+            service,
+        }
+    }
+
+    /// Identifies the client of the request to the service,
+    /// which is anonymous while the authentication is disabled.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn identify<T>(
+        &self,
+        service: &str,
+        request: &Request<T>,
+    ) -> Result<ClientIdentity, Status> {
+        if self.is_enabled() {
+            self.authenticate(service, request.metadata())
+        } else {
+            Ok(ClientIdentity {
+                name: client_identity(request),
+                role: self.anonymous_role,
+            })
+        }
+    }
+
+    /// Authenticates the bearer token in the metadata for the service.
+    #[allow(clippy::result_large_err)]
+    fn authenticate(
+        &self,
+        service: &str,
+        metadata: &MetadataMap,
+    ) -> Result<ClientIdentity, Status> {
+        let token = bearer_token(metadata)?;
+
+        let principal = match self.keys.get(&hash(token)) {
+            Some(key) => Principal {
+                identity: key.identity.clone(),
+                services: key.services.clone(),
+                role: key.role,
+            },
+            None => self.verify_jwt(token)?,
+        };
+
+        if !principal.services.is_empty() && !principal.services.iter().any(|s| s == service) {
+            return Err(Status::new(
+                Code::PermissionDenied,
+                format!(
+                    "{} is not allowed to access {}",
+                    principal.identity, service
+                ),
+            ));
+        }
+
+        Ok(ClientIdentity {
+            name: principal.identity,
+            role: principal.role,
+        })
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn verify_jwt(&self, token: &str) -> Result<Principal, Status> {
+        let invalid = || Status::new(Code::Unauthenticated, "Invalid token".to_string());
+
+        let Some(jwks) = &self.jwks else {
+            return Err(invalid());
+        };
+
+        let header = decode_header(token).map_err(|_| invalid())?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            // A single key does not need the key ID
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        }
+        .ok_or_else(invalid)?;
+        if !self.is_allowed(jwk.common.algorithm, header.alg) {
+            return Err(Status::new(
+                Code::Unauthenticated,
+                format!("Invalid token: {:?} is not allowed", header.alg),
+            ));
+        }
+        let key = DecodingKey::from_jwk(jwk).map_err(|_| invalid())?;
+
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+
+        let claims = decode::<Claims>(token, &key, &validation)
+            .map_err(|error| {
+                Status::new(Code::Unauthenticated, format!("Invalid token: {}", error))
+            })?
+            .claims;
+
+        Ok(Principal {
+            identity: claims.sub,
+            services: claims.services.unwrap_or_default(),
+            role: claims.role.unwrap_or_default(),
+        })
+    }
+
+    /// Whether the algorithm in the header of a JWT is allowed for the key,
+    /// pinned to the `alg` of the JWK and then restricted by the config.
+    fn is_allowed(&self, key_algorithm: Option<Algorithm>, algorithm: Algorithm) -> bool {
+        match key_algorithm {
+            Some(key_algorithm) if key_algorithm != algorithm => false,
+            Some(_) => self.algorithms.is_empty() || self.algorithms.contains(&algorithm),
+            None => self.algorithms.contains(&algorithm),
+        }
+    }
+}
+
+/// Interceptor which attaches the identity of the authenticated client to the request.
+#[derive(Clone)]
+pub(crate) struct AuthInterceptor {
+    authenticator: Arc<Authenticator>,
+    service: &'static str,
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let identity = self.authenticator.identify(self.service, &request)?;
+        request.extensions_mut().insert(identity);
+
+        Ok(request)
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn bearer_token(metadata: &MetadataMap) -> Result<&str, Status> {
+    let unauthenticated = |message: &str| Status::new(Code::Unauthenticated, message.to_string());
+
+    let value = metadata
+        .get("authorization")
+        .ok_or_else(|| unauthenticated("No authorization"))?
+        .to_str()
+        .map_err(|_| unauthenticated("Invalid authorization"))?;
+
+    value
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| unauthenticated("No bearer token"))
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+    const ISSUER: &str = "https://auth.example.com";
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        iss: &'a str,
+        exp: u64,
+    }
+
+    fn authenticator() -> Authenticator {
+        let jwks = serde_json::json!({
+            "keys": [{
+                "kty": "oct",
+                "kid": "test",
+                "alg": "HS256",
+                // Base64 of SECRET
+                "k": "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=",
+            }]
+        });
+        let config = AuthConfig {
+            keys: vec![ApiKeyConfig {
+                identity: "alice".to_string(),
+                sha256: hash("alice-key").to_uppercase(),
+                services: vec!["chat.Chat".to_string()],
+                role: Role::Operator,
+            }],
+            issuer: Some(ISSUER.to_string()),
+            ..Default::default()
+        };
+        Authenticator {
+            jwks: Some(serde_json::from_value(jwks).unwrap()),
+            ..Authenticator::new(&config).unwrap()
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn jwt(algorithm: Algorithm, kid: &str, issuer: &str, exp: u64) -> String {
+        let header = Header {
+            kid: Some(kid.to_string()),
+            ..Header::new(algorithm)
+        };
+        let claims = TestClaims {
+            sub: "bob",
+            iss: issuer,
+            exp,
+        };
+        encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    fn metadata(token: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            "authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        metadata
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn authenticate(token: &str) -> Result<ClientIdentity, Status> {
+        authenticator().authenticate("chat.Chat", &metadata(token))
+    }
+
+    #[test]
+    fn api_keys_are_matched_by_hash() {
+        let identity = authenticate("alice-key").unwrap();
+        assert_eq!(identity.name, "alice");
+        assert_eq!(identity.role, Role::Operator);
+
+        let status = authenticate("alice-key2").unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let status = authenticator()
+            .authenticate("speak.Speak", &metadata("alice-key"))
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+
+    #[test]
+    fn valid_jwt_is_accepted() {
+        let identity = authenticate(&jwt(Algorithm::HS256, "test", ISSUER, now() + 60)).unwrap();
+        assert_eq!(identity.name, "bob");
+        assert_eq!(identity.role, Role::User);
+    }
+
+    #[test]
+    fn expired_jwt_is_rejected() {
+        let status =
+            authenticate(&jwt(Algorithm::HS256, "test", ISSUER, now() - 3600)).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+
+    #[test]
+    fn jwt_of_other_issuer_is_rejected() {
+        let token = jwt(
+            Algorithm::HS256,
+            "test",
+            "https://other.example.com",
+            now() + 60,
+        );
+        let status = authenticate(&token).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+
+    #[test]
+    fn jwt_of_unknown_kid_is_rejected() {
+        let status = authenticate(&jwt(Algorithm::HS256, "other", ISSUER, now() + 60)).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+
+    #[test]
+    fn jwt_algorithm_is_pinned_to_the_key() {
+        let status = authenticate(&jwt(Algorithm::HS384, "test", ISSUER, now() + 60)).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let mut authenticator = authenticator();
+        let jwks = authenticator.jwks.as_mut().unwrap();
+        jwks.keys[0].common.algorithm = None;
+        let token = jwt(Algorithm::HS256, "test", ISSUER, now() + 60);
+        assert!(authenticator
+            .authenticate("chat.Chat", &metadata(&token))
+            .is_err());
+
+        authenticator.algorithms = vec![Algorithm::HS256];
+        assert!(authenticator
+            .authenticate("chat.Chat", &metadata(&token))
+            .is_ok());
+    }
+
+    #[test]
+    fn clients_are_anonymous_without_authentication() {
+        let authenticator = Authenticator::new(&AuthConfig {
+            anonymous_role: Role::Admin,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(!authenticator.is_enabled());
+
+        let request = Request::new(());
+        let identity = authenticator.identify("chat.Chat", &request).unwrap();
+        assert_eq!(identity.name, "unknown");
+        assert_eq!(identity.role, Role::Admin);
+
+        // The bearer token is ignored
+        let mut request = Request::new(());
+        *request.metadata_mut() = metadata("alice-key");
+        let identity = authenticator.identify("speak.Speak", &request).unwrap();
+        assert_eq!(identity.name, "unknown");
+    }
+}
@@ -1,10 +1,22 @@
-use std::{env, path::PathBuf};
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Protos are only for the gRPC server
+    #[cfg(feature = "server")]
+    compile_protos()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+fn compile_protos() -> Result<(), Box<dyn std::error::Error>> {
+    use std::{env, path::PathBuf};
+
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
 
+    // Messages in JSON of the REST gateway
     tonic_build::configure()
         .build_server(true)
+        .message_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .message_attribute(".", "#[serde(default)]")
         .file_descriptor_set_path(out_dir.join("chat_descriptor.bin"))
         .out_dir(out_dir.clone())
         .compile(&["src/chat/chat.proto"], &["proto"])
@@ -12,10 +24,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tonic_build::configure()
         .build_server(true)
+        .message_attribute("speak.SpeakContent", "#[derive(serde::Deserialize)]")
+        .message_attribute("speak.SpeakContent", "#[serde(default)]")
         .file_descriptor_set_path(out_dir.clone().join("speak_descriptor.bin"))
-        .out_dir(out_dir)
+        .out_dir(out_dir.clone())
         .compile(&["src/speak/speak.proto"], &["proto"])
         .unwrap_or_else(|e| panic!("protobuf compile error: {}", e));
 
+    tonic_build::configure()
+        .build_server(true)
+        .file_descriptor_set_path(out_dir.clone().join("accounting_descriptor.bin"))
+        .out_dir(out_dir.clone())
+        .compile(&["src/accounting/accounting.proto"], &["proto"])
+        .unwrap_or_else(|e| panic!("protobuf compile error: {}", e));
+
+    tonic_build::configure()
+        .build_server(true)
+        .file_descriptor_set_path(out_dir.clone().join("admin_descriptor.bin"))
+        .out_dir(out_dir)
+        .compile(&["src/admin/admin.proto"], &["proto"])
+        .unwrap_or_else(|e| panic!("protobuf compile error: {}", e));
+
     Ok(())
 }
@@ -1 +1,2 @@
+pub(super) mod continuation;
 pub(super) mod my_chat;
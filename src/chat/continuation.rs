@@ -0,0 +1,161 @@
+use crate::chat_gpt_api::client::{ChoiceDelta, StreamedCompletion, Upstream};
+use crate::chat_gpt_api::specification::{CompletionResult, Message, Options, Role};
+use crate::turn::{Completion, Delivery, Turn};
+use anyhow::Result;
+use tokio::sync::mpsc;
+
+const CONTINUE_PROMPT: &str =
+    "Continue exactly where you left off without repeating what you have already written.";
+
+/// Completes the chat, continuing the answer while it is cut off by `max_tokens`.
+///
+/// The pieces are joined into the first choice and the usage is summed over all completions.
+pub(crate) async fn complete_chat_continued(
+    upstream: &Upstream,
+    mut options: Options,
+    max_continuations: u32,
+) -> Result<CompletionResult> {
+    let messages = options.messages.clone();
+    let mut result = upstream.complete_chat(options.clone()).await?;
+    let mut answer = first_content(&result);
+
+    for _ in 0..max_continuations {
+        if !is_truncated(&result) {
+            break;
+        }
+
+        options.messages = continuation_messages(&messages, &answer);
+        let next = upstream.complete_chat(options.clone()).await?;
+        answer.push_str(&first_content(&next));
+
+        result.id = next.id;
+        result.model = next.model;
+        result.usage.prompt_tokens += next.usage.prompt_tokens;
+        result.usage.completion_tokens += next.usage.completion_tokens;
+        result.usage.total_tokens += next.usage.total_tokens;
+        if let (Some(choice), Some(next_choice)) =
+            (result.choices.first_mut(), next.choices.into_iter().next())
+        {
+            choice.finish_reason = next_choice.finish_reason;
+        }
+    }
+
+    if let Some(choice) = result.choices.first_mut() {
+        choice.message.content = Some(answer);
+    }
+
+    Ok(result)
+}
+
+/// Streams the completion in the turn, continuing the answer while it is cut off by `max_tokens`.
+///
+/// The finish reason "length" of the pieces to be continued is not forwarded,
+/// so the client receives the joined answer as a single stream.
+/// If a piece fails, the answer sent to the client so far is kept in the failed completion.
+pub(crate) async fn stream_continued(
+    turn: &mut Turn,
+    tx: mpsc::UnboundedSender<Result<ChoiceDelta>>,
+    mut options: Options,
+    max_continuations: u32,
+    delivery: &Delivery,
+) -> Completion {
+    let messages = options.messages.clone();
+    let mut joined = StreamedCompletion::default();
+    let mut remaining = max_continuations;
+
+    loop {
+        let (piece_tx, mut piece_rx) = mpsc::unbounded_channel::<Result<ChoiceDelta>>();
+        let continues = remaining > 0;
+
+        let forward = async {
+            // Contents of the choices sent in this piece
+            let mut sent: Vec<String> = Vec::new();
+            while let Some(delta) = piece_rx.recv().await {
+                let delta = delta.map(|mut delta| {
+                    if continues && delta.finish_reason.as_deref() == Some("length") {
+                        delta.finish_reason = None;
+                    }
+                    let index = delta.index as usize;
+                    if sent.len() <= index {
+                        sent.resize(index + 1, String::new());
+                    }
+                    sent[index].push_str(&delta.content);
+                    delta
+                });
+                let _ = tx.send(delta);
+            }
+            sent
+        };
+
+        let (completion, sent) =
+            tokio::join!(turn.stream(piece_tx, options.clone(), delivery), forward);
+
+        let piece = match completion {
+            Completion::Finished(piece) => piece,
+            Completion::Failed(_) => return Completion::Failed(sent_contents(&joined, sent)),
+            Completion::Interrupted(_) => return completion,
+        };
+
+        let Some(choice) = piece.choices.into_iter().next() else {
+            return Completion::Finished(joined);
+        };
+        match joined.choices.first_mut() {
+            None => joined.choices.push(choice.clone()),
+            Some(joined_choice) => {
+                joined_choice.content.push_str(&choice.content);
+                joined_choice.finish_reason = choice.finish_reason.clone();
+            }
+        }
+        joined.id = piece.id;
+        joined.model = piece.model;
+        joined.usage.prompt_tokens += piece.usage.prompt_tokens;
+        joined.usage.completion_tokens += piece.usage.completion_tokens;
+        joined.usage.total_tokens += piece.usage.total_tokens;
+
+        if !continues || choice.finish_reason.as_deref() != Some("length") {
+            return Completion::Finished(joined);
+        }
+
+        remaining -= 1;
+        options.messages = continuation_messages(&messages, &joined.choices[0].content);
+    }
+}
+
+/// Contents of the choices sent to the client, the ones of the finished pieces followed by the failed one.
+fn sent_contents(joined: &StreamedCompletion, mut sent: Vec<String>) -> Vec<String> {
+    for choice in &joined.choices {
+        let index = choice.index as usize;
+        if sent.len() <= index {
+            sent.resize(index + 1, String::new());
+        }
+        sent[index].insert_str(0, &choice.content);
+    }
+    if sent.iter().all(|content| content.is_empty()) {
+        sent.clear();
+    }
+    sent
+}
+
+fn is_truncated(result: &CompletionResult) -> bool {
+    result
+        .choices
+        .first()
+        .filter(|choice| choice.finish_reason == "length")
+        .is_some()
+}
+
+fn first_content(result: &CompletionResult) -> String {
+    result
+        .choices
+        .first()
+        .and_then(|choice| choice.message.content.clone())
+        .unwrap_or_default()
+}
+
+/// Messages asking the model to continue the answer so far.
+fn continuation_messages(messages: &[Message], answer: &str) -> Vec<Message> {
+    let mut messages = messages.to_vec();
+    messages.push(Message::new(Role::Assistant, answer));
+    messages.push(Message::new(Role::User, CONTINUE_PROMPT));
+    messages
+}
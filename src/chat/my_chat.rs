@@ -1,22 +1,32 @@
-pub(crate) mod chat_rpc {
+/// Messages, client and server generated from chat.proto.
+pub mod chat_rpc {
+    // Documented by the comments of the proto
+    #![allow(missing_docs)]
     tonic::include_proto!("chat");
 
     pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
         tonic::include_file_descriptor_set!("chat_descriptor");
 }
 
-use crate::api_state::ApiState;
-use crate::chat_gpt_api::client::{complete_chat, complete_chat_stream};
+use crate::api_state::{ApiState, Prompt};
+use crate::chat::continuation::{complete_chat_continued, stream_continued};
+use crate::chat_gpt_api::client::StreamedCompletion;
 use crate::chat_gpt_api::memory::Memory;
-use crate::chat_gpt_api::specification::{Message, Options, Role};
+use crate::chat_gpt_api::specification::{CompletionResult, Message, Options, Role};
 use crate::error_conversion::map_anyhow_error_to_grpc_status;
+use crate::identity::{client_identity, client_of};
+use crate::redaction::redact;
+use crate::session::session_id_of;
+use crate::turn::{begin_turn, interrupted, Completion, Delivery};
 use chat_rpc::chat_server::Chat;
-use futures_util::stream::StreamExt;
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
 use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
 use tonic::{Request, Response, Status};
+use tracing::Instrument;
 
 pub struct MyChat {
     pub(crate) state: Arc<Mutex<ApiState>>,
@@ -25,81 +35,111 @@ pub struct MyChat {
 #[tonic::async_trait]
 impl Chat for MyChat {
     // grpcurl -plaintext -d '{ "message": "Hello!" }' localhost:8000 chat.Chat/CompleteChat
+    #[tracing::instrument(
+        name = "chat.Chat/CompleteChat",
+        skip_all,
+        fields(session_id = %request.get_ref().session_id, client = %client_identity(&request))
+    )]
     async fn complete_chat(
         &self,
         request: Request<chat_rpc::ChatRequest>,
     ) -> Result<Response<chat_rpc::ChatResponse>, Status> {
-        let mut state = self.state.lock().await;
-
-        let address = request.remote_addr();
-        println!(
-            "Got a request to complete chat: {:?} from {:?}",
-            request, address
+        tracing::info!(
+            message = %redact(&request.get_ref().message),
+            n = request.get_ref().n,
+            "Got a request to complete chat"
         );
 
-        state.context_memory.add(Message {
-            role: Role::User.parse_to_string().unwrap(),
-            content: Some(request.into_inner().message),
-            name: None,
-            function_call: None,
-        });
+        let identity = client_of(&request);
+        let client = identity.name.clone();
+        let request = request.into_inner();
+        let session_id = session_id_of(request.session_id, &identity);
+
+        let policy = {
+            let mut state = self.state.lock().await;
+            state.authorize_session(&session_id, &identity)?;
+            state.check_quota(&session_id, &client)?;
+            state.barge_in
+        };
+        let mut turn = begin_turn(&self.state, &session_id, policy).await?;
+
+        // The state is not locked during the completion
+        let (options, max_continuations, upstream) = {
+            let mut state = self.state.lock().await;
+            let max_continuations =
+                max_continuations(&state, request.continue_on_length, request.n);
+
+            state
+                .session(&session_id)
+                .context_memory
+                .add(Message::new(Role::User, request.message));
+
+            (
+                chat_options(&mut state, &session_id, request.n, request.max_tokens),
+                max_continuations,
+                Arc::clone(&state.upstream),
+            )
+        };
+        let model = options.model.clone();
+        let messages = options.messages.clone();
+
+        let result = turn
+            .complete(async {
+                complete_chat_continued(&upstream, options, max_continuations)
+                    .await
+                    .map_err(|error| {
+                        let error = anyhow::anyhow!("Error in complete_chat: {:?}", error);
+                        map_anyhow_error_to_grpc_status(error)
+                    })
+            })
+            .await;
 
-        let context = state.context_memory.get();
-        let messages = build_messages(state.prompt.clone(), context.clone());
-
-        let options: Options = Options {
-            model: state.model.parse_to_string().unwrap(),
-            messages,
-            functions: None,
-            function_call: None,
-            temperature: None,
-            top_p: None,
-            n: None,
-            stream: None,
-            stop: None,
-            max_tokens: None,
-            presence_penalty: None,
-            frequency_penalty: None,
-            logit_bias: None,
-            user: None,
+        let mut state = self.state.lock().await;
+        let response = match result {
+            None => {
+                // Only the prompt is estimated because nothing has been delivered
+                let completion = Completion::Interrupted(Vec::new());
+                state.record_completion(&session_id, &client, &model, &messages, &completion);
+                turn.finish(&mut state, None);
+                return Err(interrupted());
+            }
+            Some(Err(status)) => {
+                turn.finish(&mut state, None);
+                return Err(status);
+            }
+            Some(Ok(response)) => response,
         };
+        state.record_usage(&session_id, &client, &model, &response.usage, false);
 
-        match complete_chat(options, true).await {
-            Err(error) => {
-                let error = anyhow::anyhow!("Error in complete_chat: {:?}", error);
-                Err(map_anyhow_error_to_grpc_status(error))
+        let response = match chat_response(response) {
+            Err(status) => {
+                turn.finish(&mut state, None);
+                return Err(status);
             }
-            Ok(response) => match response.choices.get(0) {
-                None => Err(Status::new(
-                    tonic::Code::Internal,
-                    "No choices in response".to_string(),
-                )),
-                Some(choice) => match &choice.message.content {
-                    None => Err(Status::new(
-                        tonic::Code::Internal,
-                        "No content in response".to_string(),
-                    )),
-                    // Success
-                    Some(content) => {
-                        state.context_memory.add(Message {
-                            role: Role::Assistant.parse_to_string().unwrap(),
-                            content: Some(content.to_string()),
-                            name: None,
-                            function_call: None,
-                        });
-
-                        println!(
-                            "Responding to complete chat with: {:?} to {:?}",
-                            response, address
-                        );
-
-                        Ok(Response::new(chat_rpc::ChatResponse {
-                            response: content.to_string(),
-                        }))
-                    }
-                },
-            },
+            Ok(response) => response,
+        };
+
+        // Success
+        if response.choices.len() == 1 {
+            turn.finish(&mut state, Some(response.response.clone()));
+        } else {
+            // Wait for the client to commit one of the choices
+            state.session(&session_id).pending_choices = response
+                .choices
+                .iter()
+                .map(|choice| (choice.index, choice.content.clone()))
+                .collect();
+            turn.finish(&mut state, None);
         }
+
+        tracing::info!(
+            response = %redact(&response.response),
+            finish_reason = %response.finish_reason,
+            choices = response.choices.len(),
+            "Responding to complete chat"
+        );
+
+        Ok(Response::new(response))
     }
 
     type CompleteChatStreamingStream = Pin<
@@ -112,6 +152,12 @@ impl Chat for MyChat {
     >;
 
     // grpcurl -plaintext -d '{ "message": "Hello!" }' localhost:8000 chat.Chat/CompleteChatStreaming
+    #[tracing::instrument(
+        name = "chat.Chat/CompleteChatStreaming",
+        skip_all,
+        fields(session_id = %request.get_ref().session_id, client = %client_identity(&request))
+    )]
+    #[allow(clippy::result_large_err)]
     async fn complete_chat_streaming(
         &self,
         request: Request<chat_rpc::ChatRequest>,
@@ -119,83 +165,262 @@ impl Chat for MyChat {
         let (tx, rx) = mpsc::unbounded_channel();
         let state = Arc::clone(&self.state);
 
-        let address = request.remote_addr();
-        println!(
-            "Got a request to complete chat streaming: {:?} from {:?}",
-            request, address
+        tracing::info!(
+            message = %redact(&request.get_ref().message),
+            n = request.get_ref().n,
+            "Got a request to complete chat streaming"
         );
 
-        tokio::spawn(async move {
-            let mut state = state.lock().await;
-
-            state.context_memory.add(Message {
-                role: Role::User.parse_to_string().unwrap(),
-                content: Some(request.into_inner().message),
-                name: None,
-                function_call: None,
-            });
-
-            let options = Options {
-                model: state.model.parse_to_string().unwrap(),
-                messages: state.context_memory.get(),
-                functions: None,
-                function_call: None,
-                temperature: None,
-                top_p: None,
-                n: None,
-                stream: Some(true),
-                stop: None,
-                max_tokens: None,
-                presence_penalty: None,
-                frequency_penalty: None,
-                logit_bias: None,
-                user: None,
-            };
-
-            if let Ok(total_message) = complete_chat_stream(tx.clone(), options, true).await {
-                state.context_memory.add(Message {
-                    role: Role::Assistant.parse_to_string().unwrap(),
-                    content: Some(total_message),
-                    name: None,
-                    function_call: None,
-                });
+        let identity = client_of(&request);
+        let client = identity.name.clone();
+
+        let (summary_tx, summary_rx) = oneshot::channel();
+        let delivery = Delivery::default();
+        let turn_delivery = delivery.clone();
+
+        tokio::spawn(
+            async move {
+                let request = request.into_inner();
+                let session_id = session_id_of(request.session_id, &identity);
+
+                let policy = {
+                    let mut state = state.lock().await;
+                    if let Err(status) = state
+                        .authorize_session(&session_id, &identity)
+                        .map(|_| ())
+                        .and_then(|_| state.check_quota(&session_id, &client))
+                    {
+                        let _ = tx.send(Err(anyhow::Error::new(status)));
+                        return;
+                    }
+                    state.barge_in
+                };
+                let mut turn = match begin_turn(&state, &session_id, policy).await {
+                    Err(status) => {
+                        let _ = tx.send(Err(anyhow::Error::new(status)));
+                        return;
+                    }
+                    Ok(turn) => turn,
+                };
+
+                let (options, max_continuations) = {
+                    let mut state = state.lock().await;
+                    let max_continuations =
+                        max_continuations(&state, request.continue_on_length, request.n);
+
+                    state
+                        .session(&session_id)
+                        .context_memory
+                        .add(Message::new(Role::User, request.message));
+
+                    let options =
+                        chat_options(&mut state, &session_id, request.n, request.max_tokens)
+                            .stream(true);
+
+                    (options, max_continuations)
+                };
+
+                let model = options.model.clone();
+                let messages = options.messages.clone();
+                let completion = stream_continued(
+                    &mut turn,
+                    tx.clone(),
+                    options,
+                    max_continuations,
+                    &turn_delivery,
+                )
+                .await;
+                match &completion {
+                    Completion::Finished(streamed) => {
+                        let _ = summary_tx.send(summarize(streamed));
+                    }
+                    Completion::Interrupted(_) => {
+                        let _ = tx.send(Err(anyhow::Error::new(interrupted())));
+                    }
+                    Completion::Failed(_) => {}
+                }
+
+                let mut state = state.lock().await;
+                state.record_completion(&session_id, &client, &model, &messages, &completion);
+                if request.n > 1 {
+                    // Wait for the client to commit one of the choices
+                    state.session(&session_id).pending_choices = completion.choices();
+                }
+                turn.finish(&mut state, completion.reply(request.n));
             }
-        });
+            // The span closes when the stream finishes
+            .instrument(tracing::Span::current()),
+        );
 
-        println!("Responding to complete chat streaming to {:?}.", address);
+        tracing::info!("Responding to complete chat streaming");
 
         // Wrap the receiver in a UnboundedReceiverStream
         let rx = UnboundedReceiverStream::new(rx);
 
-        let output_stream = rx.map(|result| {
-            if let Err(error) = result {
-                Err(map_anyhow_error_to_grpc_status(error))
-            } else {
+        let output_stream = rx.map(move |result| match result {
+            Err(error) => Err(map_anyhow_error_to_grpc_status(error)),
+            // Deltas after an interruption are not delivered
+            Ok(delta) if !delivery.deliver(delta.index, &delta.content) => Err(interrupted()),
+            Ok(delta) => Ok(chat_rpc::ChatStreamingResponse {
+                delta: delta.content,
+                index: delta.index as u32,
+                finish_reason: delta.finish_reason.unwrap_or_default(),
+                summary: None,
+            }),
+        });
+
+        // Finally send the summary if the completion has finished
+        let summary_stream = stream::once(summary_rx).filter_map(|summary| {
+            future::ready(summary.ok().map(|summary| {
                 Ok(chat_rpc::ChatStreamingResponse {
-                    delta: result.unwrap(),
+                    summary: Some(summary),
+                    ..Default::default()
                 })
-            }
+            }))
         });
 
         Ok(Response::new(
-            Box::pin(output_stream) as Self::CompleteChatStreamingStream
+            Box::pin(output_stream.chain(summary_stream)) as Self::CompleteChatStreamingStream
         ))
     }
+
+    // grpcurl -plaintext -d '{ "session_id": "alice", "index": 1 }' localhost:8000 chat.Chat/CommitChoice
+    #[tracing::instrument(
+        name = "chat.Chat/CommitChoice",
+        skip_all,
+        fields(session_id = %request.get_ref().session_id, client = %client_identity(&request))
+    )]
+    async fn commit_choice(
+        &self,
+        request: Request<chat_rpc::ChoiceSelection>,
+    ) -> Result<Response<chat_rpc::ChoiceSelection>, Status> {
+        let mut state = self.state.lock().await;
+
+        tracing::info!(
+            index = request.get_ref().index,
+            "Got a request to commit choice"
+        );
+
+        let identity = client_of(&request);
+        let mut selection = request.into_inner();
+        selection.session_id = session_id_of(selection.session_id, &identity);
+        let session = state.authorize_session(&selection.session_id, &identity)?;
+
+        if session.pending_choices.is_empty() {
+            return Err(Status::new(
+                tonic::Code::FailedPrecondition,
+                "No choices to commit".to_string(),
+            ));
+        }
+        // Looked up by the index of the choice received by the client
+        let Some((_, content)) = session
+            .pending_choices
+            .iter()
+            .find(|(index, _)| *index == selection.index)
+        else {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                format!("Choice not found: {}", selection.index),
+            ));
+        };
+
+        session
+            .context_memory
+            .add(Message::new(Role::Assistant, content.clone()));
+        session.pending_choices.clear();
+
+        Ok(Response::new(selection))
+    }
+}
+
+/// Response of the chat with the choices ordered by the index.
+#[allow(clippy::result_large_err)]
+fn chat_response(response: CompletionResult) -> Result<chat_rpc::ChatResponse, Status> {
+    let mut choices = Vec::new();
+    for choice in &response.choices {
+        match &choice.message.content {
+            None => {
+                return Err(Status::new(
+                    tonic::Code::Internal,
+                    "No content in response".to_string(),
+                ))
+            }
+            Some(content) => choices.push(chat_rpc::Choice {
+                index: choice.index as u32,
+                content: content.to_string(),
+                finish_reason: choice.finish_reason.clone(),
+            }),
+        }
+    }
+    choices.sort_by_key(|choice| choice.index);
+
+    let (content, finish_reason) = match choices.first() {
+        None => {
+            return Err(Status::new(
+                tonic::Code::Internal,
+                "No choices in response".to_string(),
+            ))
+        }
+        Some(choice) => (choice.content.clone(), choice.finish_reason.clone()),
+    };
+
+    Ok(chat_rpc::ChatResponse {
+        response: content,
+        choices,
+        finish_reason,
+        id: response.id,
+        model: response.model,
+        usage: Some(chat_rpc::Usage {
+            prompt_tokens: response.usage.prompt_tokens,
+            completion_tokens: response.usage.completion_tokens,
+            total_tokens: response.usage.total_tokens,
+        }),
+    })
 }
 
-fn build_messages(prompt: String, context: Vec<Message>) -> Vec<Message> {
-    let mut messages = Vec::new();
+fn summarize(completion: &StreamedCompletion) -> chat_rpc::StreamingSummary {
+    let choices: Vec<chat_rpc::FinishedChoice> = completion
+        .choices
+        .iter()
+        .map(|choice| chat_rpc::FinishedChoice {
+            index: choice.index as u32,
+            finish_reason: choice.finish_reason.clone().unwrap_or_default(),
+        })
+        .collect();
 
-    messages.push(Message {
-        role: Role::System.parse_to_string().unwrap(),
-        content: Some(prompt),
-        name: None,
-        function_call: None,
-    });
+    chat_rpc::StreamingSummary {
+        id: completion.id.clone(),
+        model: completion.model.clone(),
+        truncated: choices
+            .iter()
+            .any(|choice| choice.finish_reason == "length"),
+        choices,
+        usage: Some(chat_rpc::Usage {
+            prompt_tokens: completion.usage.prompt_tokens,
+            completion_tokens: completion.usage.completion_tokens,
+            total_tokens: completion.usage.total_tokens,
+        }),
+    }
+}
 
-    for message in context {
-        messages.push(message);
+/// Continuations are opted in by the request and only for a single choice.
+fn max_continuations(state: &ApiState, continue_on_length: bool, n: u32) -> u32 {
+    if continue_on_length && n <= 1 {
+        state.max_continuations
+    } else {
+        0
     }
+}
 
-    messages
+/// Options of the chat in the session for the number of choices and the maximum tokens,
+/// where zero is the default of each.
+fn chat_options(state: &mut ApiState, session_id: &str, n: u32, max_tokens: u32) -> Options {
+    let mut options = state.options(session_id, Prompt::Chat);
+    if n > 1 {
+        options = options.n(n as u64);
+    }
+    if max_tokens > 0 {
+        options = options.max_tokens(max_tokens as u64);
+    }
+    options
 }
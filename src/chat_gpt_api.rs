@@ -1,3 +1,13 @@
-pub(super) mod client;
-pub(super) mod memory;
-pub(super) mod specification;
+/// Recording and replaying of the traffic to the API.
+#[cfg(feature = "cassettes")]
+pub mod cassette;
+/// Client of the chat completions API.
+pub mod client;
+/// Memories of the messages in conversations.
+pub mod memory;
+/// Offline mock of the API.
+pub mod mock;
+/// Requests and responses of the API.
+pub mod specification;
+/// Estimation of the tokens.
+pub mod tokens;
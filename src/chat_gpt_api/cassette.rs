@@ -0,0 +1,270 @@
+use anyhow::Result;
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Cassettes of the traffic to the upstream API in TOML like:
+///
+/// ```toml
+/// [upstream.cassettes]
+/// mode = "replay"
+/// directory = "tests/cassettes"
+/// realtime = false
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CassetteConfig {
+    /// Whether to record or replay.
+    pub mode: CassetteMode,
+    /// Directory of the cassettes as JSON files named by the hash of the request.
+    pub directory: String,
+    /// Replays the chunks at the recorded intervals, otherwise at once.
+    pub realtime: bool,
+}
+
+impl Default for CassetteConfig {
+    fn default() -> Self {
+        Self {
+            mode: CassetteMode::Replay,
+            directory: "cassettes".to_string(),
+            realtime: true,
+        }
+    }
+}
+
+/// Mode of the cassettes.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CassetteMode {
+    /// Sends the requests to the API and writes the responses to the cassettes.
+    Record,
+    /// Serves the requests from the cassettes without network.
+    Replay,
+}
+
+/// Request and raw response keeping the boundaries and the timing of the chunks.
+///
+/// The headers of the request including the API key are not recorded.
+#[derive(Serialize, Deserialize, Debug)]
+struct Cassette {
+    method: String,
+    path: String,
+    /// `Options` of the request in JSON, null for no body.
+    request: serde_json::Value,
+    status: u16,
+    content_type: Option<String>,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Chunk {
+    /// Interval from the request or the previous chunk.
+    delay_millis: u64,
+    /// Text of the chunk, where a character split across chunks is moved to the later one.
+    data: String,
+}
+
+/// Cassette of a request being recorded.
+pub(crate) struct Recording {
+    path: PathBuf,
+    cassette: Cassette,
+    started_at: Instant,
+}
+
+/// Cassettes in the directory of the config.
+pub(crate) struct Cassettes {
+    config: CassetteConfig,
+}
+
+impl Cassettes {
+    pub(crate) fn new(config: CassetteConfig) -> Result<Self> {
+        if config.mode == CassetteMode::Record {
+            std::fs::create_dir_all(&config.directory)?;
+        }
+
+        Ok(Self { config })
+    }
+
+    /// Starts the recording of the request, which is given back to be sent.
+    pub(crate) async fn start(&self, request: Request<Body>) -> Result<(Request<Body>, Recording)> {
+        let (parts, body) = request.into_parts();
+        let bytes = hyper::body::to_bytes(body).await?;
+        let json = if bytes.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::from_slice(&bytes)?
+        };
+
+        // Keys of the JSON are sorted, so the hash does not depend on the order of the fields
+        let key = format!("{} {} {}", parts.method, parts.uri.path(), json);
+        let name = hex::encode(Sha256::digest(key.as_bytes()));
+
+        let recording = Recording {
+            path: PathBuf::from(&self.config.directory).join(format!("{}.json", &name[..16])),
+            cassette: Cassette {
+                method: parts.method.to_string(),
+                path: parts.uri.path().to_string(),
+                request: json,
+                status: 0,
+                content_type: None,
+                chunks: Vec::new(),
+            },
+            started_at: Instant::now(),
+        };
+
+        Ok((Request::from_parts(parts, Body::from(bytes)), recording))
+    }
+
+    /// Forwards the response while recording it, and writes the cassette at the end of the body.
+    ///
+    /// Nothing is written if the body is cancelled or fails in the middle.
+    pub(crate) fn record(
+        &self,
+        mut recording: Recording,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        let (parts, mut body) = response.into_parts();
+        let (mut sender, forwarded) = Body::channel();
+
+        recording.cassette.status = parts.status.as_u16();
+        recording.cassette.content_type = parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+
+        tokio::spawn(async move {
+            let mut last = recording.started_at;
+            let mut pending = Vec::new();
+            while let Some(chunk) = body.data().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(error) => {
+                        tracing::warn!(%error, "Failed to record the response");
+                        sender.abort();
+                        return;
+                    }
+                };
+
+                pending.extend_from_slice(&chunk);
+                recording.cassette.chunks.push(Chunk {
+                    delay_millis: last.elapsed().as_millis() as u64,
+                    data: take_text(&mut pending),
+                });
+                last = Instant::now();
+
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+            if !pending.is_empty() {
+                recording.cassette.chunks.push(Chunk {
+                    delay_millis: 0,
+                    data: String::from_utf8_lossy(&pending).into_owned(),
+                });
+            }
+
+            let result = match serde_json::to_string_pretty(&recording.cassette) {
+                Ok(json) => tokio::fs::write(&recording.path, json)
+                    .await
+                    .map_err(anyhow::Error::new),
+                Err(error) => Err(anyhow::Error::new(error)),
+            };
+            match result {
+                Ok(_) => tracing::debug!(path = ?recording.path, "Recorded a cassette"),
+                Err(error) => {
+                    tracing::error!(%error, path = ?recording.path, "Failed to write a cassette")
+                }
+            }
+        });
+
+        Response::from_parts(parts, forwarded)
+    }
+
+    /// Serves the response of the request from the cassette.
+    pub(crate) async fn replay(&self, request: Request<Body>) -> Result<Response<Body>> {
+        let (_, recording) = self.start(request).await?;
+
+        let text = tokio::fs::read_to_string(&recording.path)
+            .await
+            .map_err(|error| {
+                anyhow::anyhow!(
+                    "No cassette {:?} for {} {}: {}",
+                    recording.path,
+                    recording.cassette.method,
+                    recording.cassette.path,
+                    error
+                )
+            })?;
+        let cassette = serde_json::from_str::<Cassette>(&text)
+            .map_err(|error| anyhow::anyhow!("Invalid cassette {:?}: {}", recording.path, error))?;
+
+        let (mut sender, body) = Body::channel();
+        let realtime = self.config.realtime;
+        tokio::spawn(async move {
+            for chunk in cassette.chunks {
+                if realtime {
+                    tokio::time::sleep(Duration::from_millis(chunk.delay_millis)).await;
+                }
+                if sender.send_data(chunk.data.into()).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut response = Response::builder().status(cassette.status);
+        if let Some(content_type) = cassette.content_type {
+            response = response.header(CONTENT_TYPE, content_type);
+        }
+
+        Ok(response.body(body)?)
+    }
+}
+
+/// Takes the text of the bytes leaving an incomplete character at the end,
+/// which is completed by the next chunk.
+fn take_text(pending: &mut Vec<u8>) -> String {
+    let end = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        // Invalid bytes in the middle are not completed by the next chunk
+        Err(error) if error.error_len().is_some() => pending.len(),
+        Err(error) => error.valid_up_to(),
+    };
+
+    let rest = pending.split_off(end);
+    let text = String::from_utf8_lossy(pending).into_owned();
+    *pending = rest;
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_character_is_moved_to_the_next_chunk() {
+        let bytes = "おはよう".as_bytes();
+        let mut pending = Vec::new();
+
+        // In the middle of "は"
+        pending.extend_from_slice(&bytes[..4]);
+        assert_eq!(take_text(&mut pending), "お");
+        assert_eq!(pending.len(), 1);
+
+        pending.extend_from_slice(&bytes[4..]);
+        assert_eq!(take_text(&mut pending), "はよう");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced() {
+        let mut pending = vec![b'a', 0xff, b'b'];
+
+        assert_eq!(take_text(&mut pending), "a\u{fffd}b");
+        assert!(pending.is_empty());
+    }
+}
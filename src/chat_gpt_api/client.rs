@@ -1,250 +1,559 @@
-use crate::chat_gpt_api::specification::{CompletionResult, Options};
+#[cfg(feature = "cassettes")]
+use crate::chat_gpt_api::cassette::{CassetteConfig, CassetteMode, Cassettes};
+use crate::chat_gpt_api::mock::{Mock, MockConfig};
+use crate::chat_gpt_api::specification::{CompletionResult, Options, Usage};
+use crate::chat_gpt_api::tokens::estimate_usage;
+#[cfg(feature = "server")]
+use crate::metrics::METRICS;
+use crate::redaction::redact;
 use anyhow::Result;
-use hyper::{Body, Client, Request};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Response};
 use hyper_tls::HttpsConnector;
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::env;
+#[cfg(feature = "server")]
+use std::time::Instant;
 use tokio::sync::mpsc;
 use tokio_stream::StreamExt;
+use tracing::field::Empty;
+use tracing::Span;
 
 use super::specification::CompletionStreamingChunk;
 
-pub(crate) async fn complete_chat(options: Options, verbose: bool) -> Result<CompletionResult> {
-    if options.stream == Some(true) {
-        let error = Err(anyhow::anyhow!(
-            "This function is only available for stream mode"
-        ));
-        eprintln!("{:?}", error);
-        return error;
+/// Configuration of the upstream API in TOML like:
+///
+/// ```toml
+/// [upstream]
+/// base_url = "http://localhost:8090/v1"
+///
+/// [upstream.cassettes]
+/// mode = "record"
+/// directory = "cassettes"
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    /// Base URL of the API compatible with OpenAI, which can be plain HTTP.
+    pub base_url: String,
+    /// Key of the API, the one of `OPENAI_API_KEY` if not set.
+    pub api_key: Option<String>,
+    /// Offline mock answering instead of the OpenAI API if set.
+    pub mock: Option<MockConfig>,
+    /// Records the traffic to the API or replays it without network if set.
+    #[cfg(feature = "cassettes")]
+    pub cassettes: Option<CassetteConfig>,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "https://api.openai.com/v1".to_string(),
+            api_key: None,
+            mock: None,
+            #[cfg(feature = "cassettes")]
+            cassettes: None,
+        }
     }
+}
 
-    let api_key = env::var("OPENAI_API_KEY")?;
+/// Client of the upstream API set up from the config.
+pub struct Upstream {
+    base_url: String,
+    sender: Sender,
+}
 
-    // HTTPS connector
-    let https = HttpsConnector::new();
+/// Sender of the requests to the upstream API.
+enum Sender {
+    OpenAi(Remote),
+    #[cfg(feature = "cassettes")]
+    Record(Remote, Cassettes),
+    #[cfg(feature = "cassettes")]
+    Replay(Cassettes),
+    Mock(Mock),
+}
 
-    // Hyper HTTP client with HTTPS support
-    let client = Client::builder().build::<_, Body>(https);
+/// HTTP client of the API with the key.
+struct Remote {
+    client: Client<HttpsConnector<HttpConnector>>,
+    api_key: String,
+}
 
-    // Serialize the payload to a string
-    let json_str = serde_json::to_string(&options)?;
+impl Remote {
+    /// Client with the key, which fails if no key is set.
+    fn new(api_key: Option<String>) -> Result<Self> {
+        let api_key = api_key
+            .ok_or_else(|| anyhow::anyhow!("OPENAI_API_KEY is not set for the upstream API"))?;
 
-    if verbose {
-        println!("Request JSON\n{}", json_str);
+        Ok(Self {
+            client: Client::builder().build::<_, Body>(HttpsConnector::new()),
+            api_key,
+        })
     }
 
-    // WebAPI URI
-    let url = "https://api.openai.com/v1/chat/completions".parse::<hyper::Uri>()?;
+    /// Sends the request with the API key.
+    async fn send(&self, request: Request<Body>) -> Result<Response<Body>> {
+        let (mut parts, body) = request.into_parts();
+        parts
+            .headers
+            .insert("Authorization", format!("Bearer {}", self.api_key).parse()?);
+
+        Ok(self
+            .client
+            .request(Request::from_parts(parts, body))
+            .await?)
+    }
+}
 
-    // Create HTTP POST request
-    let request = Request::post(url)
-        .header("Authorization", "Bearer ".to_owned() + &api_key)
-        .header("Content-Type", "application/json")
-        .body(Body::from(json_str))?;
+impl Upstream {
+    /// Sets up the upstream API, which requires the key unless it is offline.
+    pub fn new(config: UpstreamConfig) -> Result<Self> {
+        let base_url = config.base_url.trim_end_matches('/').to_string();
+        let api_key = config.api_key.or_else(|| env::var("OPENAI_API_KEY").ok());
+
+        #[cfg(feature = "cassettes")]
+        if let Some(cassettes) = config.cassettes {
+            if config.mock.is_some() {
+                return Err(anyhow::anyhow!(
+                    "Upstream API cannot be both mocked and replayed"
+                ));
+            }
+            let sender = match cassettes.mode {
+                CassetteMode::Record => {
+                    tracing::info!(directory = %cassettes.directory, "Recording upstream API");
+                    Sender::Record(Remote::new(api_key)?, Cassettes::new(cassettes)?)
+                }
+                CassetteMode::Replay => {
+                    tracing::warn!(directory = %cassettes.directory, "Upstream API is replayed");
+                    Sender::Replay(Cassettes::new(cassettes)?)
+                }
+            };
+            return Ok(Self { base_url, sender });
+        }
 
-    // Make the request
-    let response = client.request(request).await?;
+        let sender = match config.mock {
+            None => Sender::OpenAi(Remote::new(api_key)?),
+            Some(mock) => {
+                tracing::warn!("Upstream API is mocked");
+                Sender::Mock(Mock::new(mock))
+            }
+        };
 
-    // If the request is successful
-    let status = response.status();
-    if status.is_success() {
-        // Read the response body
-        let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(Self { base_url, sender })
+    }
 
-        // Convert bytes to string
-        let body_string = String::from_utf8(body_bytes.to_vec())?;
+    /// URL of the endpoint like "/chat/completions" of the upstream API.
+    fn endpoint(&self, path: &str) -> Result<hyper::Uri> {
+        Ok(format!("{}{}", self.base_url, path).parse::<hyper::Uri>()?)
+    }
 
-        if verbose {
-            println!("Response JSON:\n{}", body_string);
+    /// Sends the request to the upstream API.
+    async fn send(&self, request: Request<Body>) -> Result<Response<Body>> {
+        match &self.sender {
+            Sender::OpenAi(remote) => remote.send(request).await,
+            #[cfg(feature = "cassettes")]
+            Sender::Record(remote, cassettes) => {
+                let (request, recording) = cassettes.start(request).await?;
+                let response = remote.send(request).await?;
+                Ok(cassettes.record(recording, response))
+            }
+            #[cfg(feature = "cassettes")]
+            Sender::Replay(cassettes) => cassettes.replay(request).await,
+            Sender::Mock(mock) => mock.respond(request).await,
         }
-
-        // Deserialize the string to a struct
-        let body_object = serde_json::from_str::<CompletionResult>(&body_string)?;
-
-        Ok(body_object)
-    } else {
-        let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
-        let body_string = String::from_utf8(body_bytes.to_vec())?;
-        let error = anyhow::anyhow!(
-            "HTTP request failed: {}\nResponse body: {}",
-            status,
-            body_string
-        );
-
-        eprintln!("{:?}", error);
-        Err(error)
     }
-}
 
-pub(crate) async fn complete_chat_stream(
-    tx: mpsc::UnboundedSender<Result<String>>,
-    options: Options,
-    verbose: bool,
-) -> Result<String> {
-    if options.stream == Some(true) {
-        let error = Err(anyhow::anyhow!(
-            "This function is only available for stream mode"
-        ));
-        eprintln!("{:?}", error);
-        tx.send(Err(anyhow::anyhow!(
-            "This function is only available for stream mode"
-        )))?;
-        return error;
-    }
+    /// Completes the chat at once, which fails for the options in stream mode or an error of the API.
+    #[tracing::instrument(
+        name = "upstream",
+        skip_all,
+        fields(model = %options.model, stream = false, prompt_tokens = Empty, completion_tokens = Empty)
+    )]
+    pub async fn complete_chat(&self, options: Options) -> Result<CompletionResult> {
+        if options.stream == Some(true) {
+            let error = anyhow::anyhow!("This function is not available for stream mode");
+            tracing::error!(%error);
+            return Err(error);
+        }
 
-    let api_key = env::var("OPENAI_API_KEY")?;
+        // Serialize the payload to a string
+        let json_str = serde_json::to_string(&options)?;
 
-    // HTTPS connector
-    let https = HttpsConnector::new();
+        tracing::trace!(request = %redact(&json_str), "Request JSON");
 
-    // Hyper HTTP client with HTTPS support
-    let client = Client::builder().build::<_, Body>(https);
+        // WebAPI URI
+        let url = self.endpoint("/chat/completions")?;
 
-    // Serialize the payload to a string
-    let json_str = serde_json::to_string(&options)?;
+        // Create HTTP POST request
+        let request = Request::post(url)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_str))?;
 
-    if verbose {
-        println!("Request JSON\n{}", json_str);
+        // Make the request
+        #[cfg(feature = "server")]
+        let started_at = Instant::now();
+        let response = match self.send(request).await {
+            Err(error) => {
+                #[cfg(feature = "server")]
+                METRICS.count_upstream_error(&options.model, "connection");
+                return Err(error);
+            }
+            Ok(response) => response,
+        };
+
+        // If the request is successful
+        let status = response.status();
+        if status.is_success() {
+            // Read the response body
+            let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+
+            // Convert bytes to string
+            let body_string = String::from_utf8(body_bytes.to_vec())?;
+
+            tracing::trace!(response = %redact(&body_string), "Response JSON");
+
+            // Deserialize the string to a struct
+            let body_object = serde_json::from_str::<CompletionResult>(&body_string)?;
+
+            let span = Span::current();
+            span.record("prompt_tokens", body_object.usage.prompt_tokens);
+            span.record("completion_tokens", body_object.usage.completion_tokens);
+
+            #[cfg(feature = "server")]
+            METRICS.observe_upstream(
+                &options.model,
+                false,
+                started_at,
+                started_at,
+                body_object.usage.completion_tokens,
+            );
+
+            Ok(body_object)
+        } else {
+            let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+            let body_string = String::from_utf8(body_bytes.to_vec())?;
+            let error = anyhow::anyhow!(
+                "HTTP request failed: {}\nResponse body: {}",
+                status,
+                body_string
+            );
+
+            tracing::error!(%status, body = %body_string, "HTTP request failed");
+            #[cfg(feature = "server")]
+            METRICS.count_upstream_error(&options.model, status.as_str());
+            Err(error)
+        }
     }
 
-    // WebAPI URI
-    let url = "https://api.openai.com/v1/chat/completions".parse::<hyper::Uri>()?;
-
-    // Create HTTP POST request
-    let request = Request::post(url)
-        .header("Authorization", "Bearer ".to_owned() + &api_key)
-        .header("Content-Type", "application/json")
-        .body(Body::from(json_str))?;
-
-    // Make the request
-    match client.request(request).await {
-        Err(error) => {
-            eprintln!("Failed to make request: {:?}", error);
-            tx.send(Err(anyhow::Error::new(error)))?;
-            Err(anyhow::anyhow!("Failed to make request"))
+    /// Streams the completion, of which usage is estimated locally.
+    #[tracing::instrument(
+        name = "upstream",
+        skip_all,
+        fields(model = %options.model, stream = true, prompt_tokens = Empty, completion_tokens = Empty)
+    )]
+    pub async fn complete_chat_stream(
+        &self,
+        tx: mpsc::UnboundedSender<Result<ChoiceDelta>>,
+        options: Options,
+    ) -> Result<StreamedCompletion> {
+        if options.stream != Some(true) {
+            tracing::error!("This function is only available for stream mode");
+            tx.send(Err(anyhow::anyhow!(
+                "This function is only available for stream mode"
+            )))?;
+            return Err(anyhow::anyhow!(
+                "This function is only available for stream mode"
+            ));
         }
-        Ok(response) => {
-            // If the request is successful
-            let status = response.status();
-            if status.is_success() {
-                let mut body = hyper::body::Body::wrap_stream(response.into_body());
-                let mut total_message = "".to_string();
-
-                while let Some(chunk) = body.next().await {
-                    let chunk = chunk?;
-                    let chunk_string = String::from_utf8(chunk.to_vec())?;
-
-                    if verbose {
-                        println!("Response chunk:\n{}", chunk_string);
-                    }
 
-                    // Split the chunk by newline characters and process each line
-                    for line in chunk_string.split('\n') {
-                        if line.is_empty() {
-                            continue;
-                        }
-                        let result = process_chunk(tx.clone(), line.to_string(), verbose).await;
-                        match result {
-                            Ok(result) => {
-                                total_message.push_str(&result);
-                                if verbose {
-                                    println!("Current total message:\n{}", total_message);
-                                }
+        // Serialize the payload to a string
+        let json_str = serde_json::to_string(&options)?;
+
+        tracing::trace!(request = %redact(&json_str), "Request JSON");
+
+        // WebAPI URI
+        let url = self.endpoint("/chat/completions")?;
+
+        // Create HTTP POST request
+        let request = Request::post(url)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_str))?;
+
+        // Make the request
+        #[cfg(feature = "server")]
+        let started_at = Instant::now();
+        match self.send(request).await {
+            Err(error) => {
+                tracing::error!(%error, "Failed to make request");
+                #[cfg(feature = "server")]
+                METRICS.count_upstream_error(&options.model, "connection");
+                tx.send(Err(error))?;
+                Err(anyhow::anyhow!("Failed to make request"))
+            }
+            Ok(response) => {
+                // If the request is successful
+                let status = response.status();
+                if status.is_success() {
+                    let mut body = hyper::body::Body::wrap_stream(response.into_body());
+                    let mut completion = StreamedCompletion::default();
+                    let mut choices = BTreeMap::<u64, StreamedChoice>::new();
+                    #[cfg(feature = "server")]
+                    let mut first_token_at = None;
+
+                    while let Some(chunk) = body.next().await {
+                        let chunk = chunk?;
+                        let chunk_string = String::from_utf8(chunk.to_vec())?;
+
+                        tracing::trace!(chunk = %redact(&chunk_string), "Response chunk");
+
+                        // Split the chunk by newline characters and process each line
+                        for line in chunk_string.split('\n') {
+                            if line.is_empty() {
+                                continue;
                             }
-                            Err(error) => {
-                                eprintln!("Failed to process chunk: {:?}", error);
-                                return Err(anyhow::anyhow!("Failed to process chunk"));
+                            let result = process_chunk(tx.clone(), line.to_string()).await;
+                            match result {
+                                Ok(None) => {}
+                                Ok(Some(parsed)) => {
+                                    #[cfg(feature = "server")]
+                                    if first_token_at.is_none() && !parsed.deltas.is_empty() {
+                                        METRICS.observe_first_token(&options.model, started_at);
+                                        first_token_at = Some(Instant::now());
+                                    }
+                                    completion.id = parsed.id;
+                                    completion.model = parsed.model;
+                                    for delta in parsed.deltas {
+                                        let choice =
+                                            choices.entry(delta.index).or_insert_with(|| {
+                                                StreamedChoice {
+                                                    index: delta.index,
+                                                    ..Default::default()
+                                                }
+                                            });
+                                        choice.content.push_str(&delta.content);
+                                        if delta.finish_reason.is_some() {
+                                            choice.finish_reason = delta.finish_reason;
+                                        }
+                                    }
+                                }
+                                Err(error) => {
+                                    tracing::error!(%error, "Failed to process chunk");
+                                    return Err(anyhow::anyhow!("Failed to process chunk"));
+                                }
                             }
                         }
                     }
-                }
 
-                if verbose {
-                    println!("Result total message:\n{}", total_message);
+                    completion.choices = choices.into_values().collect();
+                    let contents: Vec<String> = completion
+                        .choices
+                        .iter()
+                        .map(|choice| choice.content.clone())
+                        .collect();
+                    completion.usage = estimate_usage(&options.messages, &contents);
+
+                    let span = Span::current();
+                    span.record("prompt_tokens", completion.usage.prompt_tokens);
+                    span.record("completion_tokens", completion.usage.completion_tokens);
+
+                    #[cfg(feature = "server")]
+                    METRICS.observe_upstream(
+                        &options.model,
+                        true,
+                        started_at,
+                        first_token_at.unwrap_or(started_at),
+                        completion.usage.completion_tokens,
+                    );
+
+                    // Finish streaming
+                    Ok(completion)
+                } else {
+                    let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+                    let body_string = String::from_utf8(body_bytes.to_vec())?;
+                    let error = anyhow::anyhow!(
+                        "HTTP request failed: {}\nResponse body: {}",
+                        status.clone(),
+                        body_string
+                    );
+
+                    tracing::error!(%status, body = %body_string, "HTTP request failed");
+                    #[cfg(feature = "server")]
+                    METRICS.count_upstream_error(&options.model, status.as_str());
+                    tx.send(Err(error))?;
+
+                    let error = anyhow::anyhow!(
+                        "HTTP request failed: {}\nResponse body: {}",
+                        status.clone(),
+                        body_string
+                    );
+                    Err(error)
                 }
-
-                // Finish streaming
-                Ok(total_message)
-            } else {
-                let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
-                let body_string = String::from_utf8(body_bytes.to_vec())?;
-                let error = anyhow::anyhow!(
-                    "HTTP request failed: {}\nResponse body: {}",
-                    status.clone(),
-                    body_string
-                );
-
-                eprintln!("{:?}", error);
-                tx.send(Err(error))?;
-
-                let error = anyhow::anyhow!(
-                    "HTTP request failed: {}\nResponse body: {}",
-                    status.clone(),
-                    body_string
-                );
-                Err(error)
             }
         }
     }
+
+    /// Checks whether the API is reachable, which fails on no response or a server error.
+    ///
+    /// Offline upstreams are always reachable, and the probes are not recorded in cassettes.
+    pub async fn check_reachability(&self) -> Result<()> {
+        let remote = match &self.sender {
+            Sender::OpenAi(remote) => remote,
+            #[cfg(feature = "cassettes")]
+            Sender::Record(remote, _) => remote,
+            #[cfg(feature = "cassettes")]
+            Sender::Replay(_) => return Ok(()),
+            Sender::Mock(_) => return Ok(()),
+        };
+        let url = self.endpoint("/models")?;
+
+        // Any response but a server error means reachable even if the key is not accepted
+        let response = remote.send(Request::get(url).body(Body::empty())?).await?;
+
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(anyhow::anyhow!("API is not available: {}", status));
+        }
+
+        Ok(())
+    }
+}
+
+/// Delta of a choice in streaming, the last one of each choice has the finish reason.
+#[derive(Clone, Debug)]
+pub struct ChoiceDelta {
+    /// Index of the choice in the choices.
+    pub index: u64,
+    /// Text following the previous deltas of the choice.
+    pub content: String,
+    /// Why the generation of the choice stopped.
+    pub finish_reason: Option<String>,
+}
+
+/// Choice joined from the deltas in streaming.
+#[derive(Clone, Debug, Default)]
+pub struct StreamedChoice {
+    /// Index of the choice in the choices.
+    pub index: u64,
+    /// Text joined from the deltas.
+    pub content: String,
+    /// Why the generation stopped, none if the stream ended without the reason.
+    pub finish_reason: Option<String>,
+}
+
+/// Completion joined from the chunks in streaming.
+#[derive(Clone, Debug, Default)]
+pub struct StreamedCompletion {
+    /// ID of the completion shared by the chunks.
+    pub id: String,
+    /// ID of the model which generated the completion.
+    pub model: String,
+    /// Choices ordered by the index.
+    pub choices: Vec<StreamedChoice>,
+    /// Estimated locally since the API does not report usage in streaming.
+    pub usage: Usage,
+}
+
+/// Deltas parsed from a line of the stream.
+struct ParsedLine {
+    id: String,
+    model: String,
+    deltas: Vec<ChoiceDelta>,
 }
 
 async fn process_chunk(
-    tx: mpsc::UnboundedSender<Result<String>>,
+    tx: mpsc::UnboundedSender<Result<ChoiceDelta>>,
     line: String,
-    verbose: bool,
-) -> Result<String> {
+) -> Result<Option<ParsedLine>> {
     if line == "data: [DONE]" {
-        if verbose {
-            println!("Finish reason: DONE");
-        }
+        tracing::debug!("Finish reason: DONE");
         // Finished
-        return Ok("".to_string());
+        return Ok(None);
     }
 
     let data = line.trim_start_matches("data: ").to_string();
 
     // Deserialize the string to a struct
-    match serde_json::from_str::<CompletionStreamingChunk>(&data) {
+    let chunk_object = match serde_json::from_str::<CompletionStreamingChunk>(&data) {
         Err(e) => {
-            eprintln!("Failed to parse JSON: {}", e);
+            tracing::error!(error = %e, "Failed to parse JSON");
             tx.send(Err(anyhow::Error::new(e)))?;
             return Err(anyhow::anyhow!("Failed to parse JSON"));
         }
-        Ok(chunk_object) => match chunk_object.choices.get(0) {
-            None => return Err(anyhow::anyhow!("No choices")),
-            Some(chunk_choice) => {
-                if chunk_choice.finish_reason.is_some() {
-                    if verbose {
-                        println!("Finish reason: {:?}", chunk_choice.finish_reason);
-                    }
-                    // Finished
-                    return Ok("".to_string());
-                }
-                if chunk_choice.delta.role.is_some() {
-                    if verbose {
-                        println!("Role: {:?}", chunk_choice.delta.role);
-                    }
-                    // Skip role
-                    return Ok("".to_string());
-                }
+        Ok(chunk_object) => chunk_object,
+    };
 
-                match chunk_choice.delta.content.clone() {
-                    None => {
-                        if let Err(e) = tx.send(Err(anyhow::anyhow!("No content"))) {
-                            eprintln!("Failed to send error: {:?}", e);
-                        }
-                        return Err(anyhow::anyhow!("No content"));
-                    }
-                    Some(content) => {
-                        if let Err(e) = tx.send(Ok(content.clone())) {
-                            eprintln!("Failed to send message: {:?}", e);
-                            return Err(anyhow::anyhow!("Failed to send message"));
-                        } else {
-                            // Succeeded to send message
-                            Ok(content)
-                        }
+    if chunk_object.choices.is_empty() {
+        return Err(anyhow::anyhow!("No choices"));
+    }
+
+    // Choices are multiplexed by the index when n > 1
+    let mut deltas = Vec::new();
+    for chunk_choice in chunk_object.choices {
+        let delta = if chunk_choice.finish_reason.is_some() {
+            tracing::debug!(
+                index = chunk_choice.index,
+                finish_reason = ?chunk_choice.finish_reason,
+                "Finish reason of choice"
+            );
+            // Finished
+            ChoiceDelta {
+                index: chunk_choice.index,
+                content: chunk_choice.delta.content.unwrap_or_default(),
+                finish_reason: chunk_choice.finish_reason,
+            }
+        } else if chunk_choice.delta.role.is_some() {
+            // Skip role
+            continue;
+        } else {
+            match chunk_choice.delta.content {
+                None => {
+                    if let Err(e) = tx.send(Err(anyhow::anyhow!("No content"))) {
+                        tracing::error!(error = %e, "Failed to send error");
                     }
+                    return Err(anyhow::anyhow!("No content"));
                 }
+                Some(content) => ChoiceDelta {
+                    index: chunk_choice.index,
+                    content,
+                    finish_reason: None,
+                },
             }
-        },
+        };
+
+        if let Err(e) = tx.send(Ok(delta.clone())) {
+            tracing::warn!(error = %e, "Failed to send message");
+            return Err(anyhow::anyhow!("Failed to send message"));
+        }
+
+        // Succeeded to send message
+        deltas.push(delta);
+    }
+
+    Ok(Some(ParsedLine {
+        id: chunk_object.id,
+        model: chunk_object.model,
+        deltas,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_gpt_api::mock::MockConfig;
+
+    #[test]
+    fn api_key_is_required_only_online() {
+        assert!(Remote::new(None).is_err());
+        assert!(Upstream::new(UpstreamConfig {
+            api_key: Some("sk-test".to_string()),
+            ..Default::default()
+        })
+        .is_ok());
+        assert!(Upstream::new(UpstreamConfig {
+            mock: Some(MockConfig::default()),
+            ..Default::default()
+        })
+        .is_ok());
     }
 }
@@ -1,19 +1,27 @@
 use crate::chat_gpt_api::specification::Message;
 use std::collections::VecDeque;
 
-pub(crate) trait Memory: Send + Clone {
+/// Memory of the messages of a conversation to send as the context of completions.
+pub trait Memory: Send + Clone {
+    /// Messages in the memory from the oldest.
     fn get(&self) -> Vec<Message>;
+    /// Adds the message as the latest, which may forget older messages.
     fn add(&mut self, message: Message);
+    /// Forgets all messages.
     fn clear(&mut self);
 }
 
-pub(crate) struct FiniteQueueMemory {
-    pub(crate) memories: VecDeque<Message>,
-    pub(crate) max_size: usize,
+/// Memory of the latest messages up to the size, which forgets the oldest ones first.
+pub struct FiniteQueueMemory {
+    /// Messages from the oldest.
+    pub memories: VecDeque<Message>,
+    /// Maximum number of the messages.
+    pub max_size: usize,
 }
 
 impl FiniteQueueMemory {
-    pub(crate) fn new(max_size: usize) -> Self {
+    /// Empty memory of the size.
+    pub fn new(max_size: usize) -> Self {
         Self {
             memories: VecDeque::new(),
             max_size,
@@ -0,0 +1,569 @@
+use crate::chat_gpt_api::specification::{
+    Choice, ChoiceChunk, CompletionResult, CompletionStreamingChunk, Delta, Function, FunctionCall,
+    FunctionCallingSpecification, Message, Options, Role,
+};
+use crate::chat_gpt_api::tokens::{estimate_tokens, estimate_usage};
+use anyhow::Result;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Configuration of the offline mock of the API in TOML like:
+///
+/// ```toml
+/// [upstream.mock]
+/// replies = ["Hello!", "How are you?"]
+/// chunk_size = 4
+/// chunk_delay_millis = 50
+/// error = "rate_limited"
+/// error_interval = 3
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MockConfig {
+    /// Replies in turn for the requests, the last user message is echoed if empty.
+    pub replies: Vec<String>,
+    /// Number of characters in a chunk of streaming.
+    pub chunk_size: usize,
+    /// Delay before each chunk of streaming.
+    pub chunk_delay_millis: u64,
+    /// Error injected into the requests.
+    pub error: Option<MockError>,
+    /// The error is injected into every this number of requests, and all of them if zero.
+    pub error_interval: u64,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            replies: Vec::new(),
+            chunk_size: 4,
+            chunk_delay_millis: 50,
+            error: None,
+            error_interval: 0,
+        }
+    }
+}
+
+/// Error injected by the mock.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MockError {
+    /// 429 Too Many Requests.
+    RateLimited,
+    /// 500 Internal Server Error.
+    ServerError,
+    /// Broken JSON in the body, in the middle of the stream in streaming.
+    Malformed,
+}
+
+/// Mock of the chat completions API answering the requests in the format of the API,
+/// so that the server can run offline through the same parsing as the real one.
+pub(crate) struct Mock {
+    config: MockConfig,
+    requests: AtomicU64,
+}
+
+impl Mock {
+    pub(crate) fn new(config: MockConfig) -> Self {
+        Self {
+            config,
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) async fn respond(&self, request: Request<Body>) -> Result<Response<Body>> {
+        // Other endpoints like the models for the health check
+        if !request.uri().path().ends_with("/chat/completions") {
+            return Ok(Response::new(Body::from("{}")));
+        }
+
+        let count = self.requests.fetch_add(1, Ordering::Relaxed);
+
+        let body = hyper::body::to_bytes(request.into_body()).await?;
+        let options = serde_json::from_slice::<Options>(&body)?;
+
+        let error = self.config.error.filter(|_| {
+            let interval = self.config.error_interval;
+            interval == 0 || count % interval == interval - 1
+        });
+        match error {
+            Some(MockError::RateLimited) => {
+                return error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate_limit_exceeded",
+                    "Rate limit reached (mock)",
+                )
+            }
+            Some(MockError::ServerError) => {
+                return error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    "The server had an error (mock)",
+                )
+            }
+            _ => {}
+        }
+        let malformed = error == Some(MockError::Malformed);
+
+        let (message, finish_reason) = self.reply(&options, count);
+        if options.stream == Some(true) {
+            Ok(self.stream(&options, message, finish_reason, malformed))
+        } else {
+            complete(&options, message, finish_reason, malformed)
+        }
+    }
+
+    /// Function call if specified by name, otherwise the scripted reply or the echo
+    /// cut off by `max_tokens`, with the finish reason.
+    ///
+    /// A reply cut off before is continued after the part in the last assistant message.
+    fn reply(&self, options: &Options, count: u64) -> (Message, &'static str) {
+        let function = match &options.function_call {
+            Some(FunctionCallingSpecification::Name(name)) => options
+                .functions
+                .iter()
+                .flatten()
+                .find(|function| &function.name == name),
+            _ => None,
+        };
+        if let Some(function) = function {
+            let message = Message::function_call(FunctionCall {
+                name: function.name.clone(),
+                arguments: arguments(function, count),
+            });
+            return (message, "function_call");
+        }
+
+        let mut content = if self.config.replies.is_empty() {
+            let user = Role::User.parse_to_string().unwrap();
+            options
+                .messages
+                .iter()
+                .rev()
+                .find(|message| message.role == user)
+                .and_then(|message| message.content.clone())
+                .unwrap_or_default()
+        } else {
+            let index = count as usize % self.config.replies.len();
+            self.config.replies[index].clone()
+        };
+
+        let assistant = Role::Assistant.parse_to_string().unwrap();
+        let previous = options
+            .messages
+            .iter()
+            .rev()
+            .find(|message| message.role == assistant)
+            .and_then(|message| message.content.as_deref())
+            .filter(|previous| content.len() > previous.len() && content.starts_with(previous));
+        if let Some(previous) = previous {
+            content = content[previous.len()..].to_string();
+        }
+
+        let mut finish_reason = "stop";
+        if let Some(max_tokens) = options.max_tokens {
+            if let Some(end) = cut_off(&content, max_tokens) {
+                content.truncate(end);
+                finish_reason = "length";
+            }
+        }
+
+        (Message::new(Role::Assistant, content), finish_reason)
+    }
+
+    /// Server-sent events of the chunks of the reply for each choice.
+    fn stream(
+        &self,
+        options: &Options,
+        message: Message,
+        finish_reason: &'static str,
+        malformed: bool,
+    ) -> Response<Body> {
+        let (mut sender, body) = Body::channel();
+
+        let id = completion_id();
+        let model = options.model.clone();
+        let n = options.n.unwrap_or(1);
+        let content: Vec<char> = message.content.unwrap_or_default().chars().collect();
+        let chunk_size = self.config.chunk_size.max(1);
+        let delay = Duration::from_millis(self.config.chunk_delay_millis);
+
+        tokio::spawn(async move {
+            let chunk = |index: u64, delta: Delta, finish_reason: Option<&str>| {
+                let chunk = CompletionStreamingChunk {
+                    id: id.clone(),
+                    object: "chat.completion.chunk".to_string(),
+                    created: now(),
+                    model: model.clone(),
+                    choices: vec![ChoiceChunk {
+                        delta,
+                        index,
+                        finish_reason: finish_reason.map(|reason| reason.to_string()),
+                    }],
+                };
+                format!("data: {}\n\n", serde_json::to_string(&chunk).unwrap())
+            };
+
+            let mut events = Vec::new();
+            for index in 0..n {
+                events.push(chunk(
+                    index,
+                    Delta {
+                        role: Some(Role::Assistant.parse_to_string().unwrap()),
+                        content: None,
+                    },
+                    None,
+                ));
+            }
+            for piece in content.chunks(chunk_size) {
+                for index in 0..n {
+                    events.push(chunk(
+                        index,
+                        Delta {
+                            role: None,
+                            content: Some(piece.iter().collect()),
+                        },
+                        None,
+                    ));
+                }
+            }
+            if malformed {
+                events.insert(events.len() / 2, "data: {\"id\": \n\n".to_string());
+            }
+            for index in 0..n {
+                events.push(chunk(
+                    index,
+                    Delta {
+                        role: None,
+                        content: None,
+                    },
+                    Some(finish_reason),
+                ));
+            }
+            events.push("data: [DONE]\n\n".to_string());
+
+            for event in events {
+                tokio::time::sleep(delay).await;
+                if sender.send_data(event.into()).await.is_err() {
+                    // Cancelled by the client
+                    return;
+                }
+            }
+        });
+
+        Response::builder()
+            .header("Content-Type", "text/event-stream")
+            .body(body)
+            .unwrap()
+    }
+}
+
+fn complete(
+    options: &Options,
+    message: Message,
+    finish_reason: &str,
+    malformed: bool,
+) -> Result<Response<Body>> {
+    let completions: Vec<String> = (0..options.n.unwrap_or(1))
+        .map(|_| message.content.clone().unwrap_or_default())
+        .collect();
+    let usage = estimate_usage(&options.messages, &completions);
+
+    let result = CompletionResult {
+        id: completion_id(),
+        object: "chat.completion".to_string(),
+        created: now(),
+        model: options.model.clone(),
+        choices: (0..options.n.unwrap_or(1))
+            .map(|index| Choice {
+                index,
+                message: message.clone(),
+                finish_reason: finish_reason.to_string(),
+            })
+            .collect(),
+        usage,
+    };
+
+    let mut json = serde_json::to_string(&result)?;
+    if malformed {
+        json.truncate(json.len() / 2);
+    }
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(json))?)
+}
+
+/// End of the content within the tokens, or None if the whole content is within them.
+fn cut_off(content: &str, max_tokens: u64) -> Option<usize> {
+    if estimate_tokens(content) <= max_tokens {
+        return None;
+    }
+
+    let end = content
+        .char_indices()
+        .map(|(index, character)| index + character.len_utf8())
+        .take_while(|end| estimate_tokens(&content[..*end]) <= max_tokens)
+        .last()
+        .unwrap_or(0);
+    Some(end)
+}
+
+/// Arguments of the function call taking a value of each enum of the parameters in turn.
+fn arguments(function: &Function, count: u64) -> String {
+    let mut arguments = serde_json::Map::new();
+    let properties = function
+        .parameters
+        .get("properties")
+        .and_then(|properties| properties.as_object());
+
+    for (name, property) in properties.into_iter().flatten() {
+        let value = match property.get("enum").and_then(|values| values.as_array()) {
+            Some(values) if !values.is_empty() => values[count as usize % values.len()].clone(),
+            _ => match property.get("type").and_then(|value| value.as_str()) {
+                Some("number") | Some("integer") => serde_json::json!(0),
+                Some("boolean") => serde_json::json!(false),
+                Some("array") => serde_json::json!([]),
+                Some("object") => serde_json::json!({}),
+                _ => serde_json::json!("mock"),
+            },
+        };
+        arguments.insert(name.clone(), value);
+    }
+
+    serde_json::Value::Object(arguments).to_string()
+}
+
+fn error_response(status: StatusCode, code: &str, message: &str) -> Result<Response<Body>> {
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": code,
+            "code": code,
+        }
+    });
+
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))?)
+}
+
+fn completion_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("chatcmpl-mock-{:x}", nanos)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock(config: MockConfig) -> Mock {
+        Mock::new(MockConfig {
+            chunk_delay_millis: 0,
+            ..config
+        })
+    }
+
+    fn options(message: &str) -> Options {
+        Options::new(
+            "gpt-3.5-turbo".to_string(),
+            vec![Message::new(Role::User, message.to_string())],
+        )
+    }
+
+    async fn respond(mock: &Mock, options: &Options) -> (StatusCode, String) {
+        let request = Request::post("http://mock/v1/chat/completions")
+            .body(Body::from(serde_json::to_string(options).unwrap()))
+            .unwrap();
+        let response = mock.respond(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn complete(mock: &Mock, options: &Options) -> CompletionResult {
+        let (status, body) = respond(mock, options).await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    /// Contents of the deltas by the index of the choice in streaming.
+    async fn stream(mock: &Mock, options: &Options) -> Vec<Vec<String>> {
+        let options = Options {
+            stream: Some(true),
+            ..options.clone()
+        };
+        let (_, body) = respond(mock, &options).await;
+
+        let mut deltas = vec![Vec::new(); options.n.unwrap_or(1) as usize];
+        for line in body.lines().filter_map(|line| line.strip_prefix("data: ")) {
+            let Ok(chunk) = serde_json::from_str::<CompletionStreamingChunk>(line) else {
+                continue;
+            };
+            for choice in chunk.choices {
+                if let Some(content) = choice.delta.content {
+                    deltas[choice.index as usize].push(content);
+                }
+            }
+        }
+        deltas
+    }
+
+    fn content(result: &CompletionResult) -> &str {
+        result.choices[0].message.content.as_deref().unwrap()
+    }
+
+    #[tokio::test]
+    async fn replies_are_cycled() {
+        let mock = mock(MockConfig {
+            replies: vec!["First".to_string(), "Second".to_string()],
+            ..Default::default()
+        });
+
+        assert_eq!(content(&complete(&mock, &options("Hi")).await), "First");
+        assert_eq!(content(&complete(&mock, &options("Hi")).await), "Second");
+        assert_eq!(content(&complete(&mock, &options("Hi")).await), "First");
+    }
+
+    #[tokio::test]
+    async fn user_message_is_echoed_without_replies() {
+        let mock = mock(MockConfig::default());
+
+        let result = complete(&mock, &options("Hello")).await;
+        assert_eq!(content(&result), "Hello");
+        assert_eq!(result.choices[0].finish_reason, "stop");
+    }
+
+    #[tokio::test]
+    async fn chunks_are_split_on_char_boundaries() {
+        let mock = mock(MockConfig {
+            chunk_size: 2,
+            ..Default::default()
+        });
+
+        let deltas = stream(&mock, &options("おはよう!")).await;
+        assert_eq!(deltas, vec![vec!["おは", "よう", "!"]]);
+    }
+
+    #[tokio::test]
+    async fn choices_are_fanned_out() {
+        let mock = mock(MockConfig::default());
+        let options = Options {
+            n: Some(3),
+            ..options("Hello")
+        };
+
+        let result = complete(&mock, &options).await;
+        let indexes: Vec<u64> = result.choices.iter().map(|choice| choice.index).collect();
+        assert_eq!(indexes, vec![0, 1, 2]);
+
+        let deltas = stream(&mock, &options).await;
+        assert_eq!(deltas.len(), 3);
+        for contents in deltas {
+            assert_eq!(contents.concat(), "Hello");
+        }
+    }
+
+    #[test]
+    fn arguments_take_enum_values_in_turn() {
+        let parameters = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "emotion": { "type": "string", "enum": ["happy", "sad"] },
+                "intensity": { "type": "number" },
+            },
+        });
+        let function = Function {
+            name: "react".to_string(),
+            description: None,
+            parameters: parameters.as_object().unwrap().clone(),
+        };
+
+        let arguments = |count| {
+            serde_json::from_str::<serde_json::Value>(&arguments(&function, count)).unwrap()
+        };
+        assert_eq!(
+            arguments(0),
+            serde_json::json!({ "emotion": "happy", "intensity": 0 })
+        );
+        assert_eq!(arguments(1)["emotion"], "sad");
+        assert_eq!(arguments(2)["emotion"], "happy");
+    }
+
+    #[tokio::test]
+    async fn errors_are_injected_at_the_interval() {
+        let mock = mock(MockConfig {
+            error: Some(MockError::RateLimited),
+            error_interval: 3,
+            ..Default::default()
+        });
+
+        let mut statuses = Vec::new();
+        for _ in 0..6 {
+            statuses.push(respond(&mock, &options("Hi")).await.0);
+        }
+        let ok = StatusCode::OK;
+        let limited = StatusCode::TOO_MANY_REQUESTS;
+        assert_eq!(statuses, vec![ok, ok, limited, ok, ok, limited]);
+    }
+
+    #[tokio::test]
+    async fn errors_are_injected_into_all_requests_without_interval() {
+        let mock = mock(MockConfig {
+            error: Some(MockError::ServerError),
+            ..Default::default()
+        });
+
+        for _ in 0..2 {
+            let (status, _) = respond(&mock, &options("Hi")).await;
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_body_is_broken_json() {
+        let mock = mock(MockConfig {
+            error: Some(MockError::Malformed),
+            ..Default::default()
+        });
+
+        let (status, body) = respond(&mock, &options("Hello")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(serde_json::from_str::<CompletionResult>(&body).is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_stream_has_a_broken_event() {
+        let mock = mock(MockConfig {
+            error: Some(MockError::Malformed),
+            ..Default::default()
+        });
+        let options = Options {
+            stream: Some(true),
+            ..options("Hello")
+        };
+
+        let (_, body) = respond(&mock, &options).await;
+        let broken: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+            .filter(|data| serde_json::from_str::<CompletionStreamingChunk>(data).is_err())
+            .collect();
+        assert_eq!(broken.len(), 1);
+        assert!(body.ends_with("data: [DONE]\n\n"));
+    }
+}
@@ -2,20 +2,30 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+/// Models of the chat completions API.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) enum Model {
+pub enum Model {
+    /// "gpt-3.5-turbo"
     Gpt35Turbo,
+    /// "gpt-3.5-turbo-0613"
     Gpt35Turbo0613,
+    /// "gpt-3.5-turbo-16k"
     Gpt35Turbo16k,
+    /// "gpt-3.5-turbo-16k-0613"
     Gpt35Turbo16k0613,
+    /// "gpt-4"
     Gpt4,
+    /// "gpt-4-0613"
     Gpt40613,
+    /// "gpt-4-32k"
     Gpt432k,
+    /// "gpt-4-32k-0613"
     Gpt432k0613,
 }
 
 impl Model {
-    pub(crate) fn parse_to_string(&self) -> Result<String> {
+    /// ID of the model in the API like "gpt-4".
+    pub fn parse_to_string(&self) -> Result<String> {
         match self {
             Model::Gpt35Turbo => Ok("gpt-3.5-turbo".to_string()),
             Model::Gpt35Turbo0613 => Ok("gpt-3.5-turbo-0613".to_string()),
@@ -28,31 +38,38 @@ impl Model {
         }
     }
 
-    // pub(crate) fn parse_to_model(input: &str) -> Result<Model> {
-    //     match input {
-    //         "gpt-3.5-turbo" => Ok(Model::Gpt35Turbo),
-    //         "gpt-3.5-turbo-0613" => Ok(Model::Gpt35Turbo0613),
-    //         "gpt-3.5-turbo-16k" => Ok(Model::Gpt35Turbo16k),
-    //         "gpt-3.5-turbo-16k-0613" => Ok(Model::Gpt35Turbo16k0613),
-    //         "gpt-4" => Ok(Model::Gpt4),
-    //         "gpt-4-0613" => Ok(Model::Gpt40613),
-    //         "gpt-4-32k" => Ok(Model::Gpt432k),
-    //         "gpt-4-32k-0613" => Ok(Model::Gpt432k0613),
-    //         _ => Err(anyhow!("Invalid model")),
-    //     }
-    // }
+    /// Model of the ID in the API, which fails for an unknown ID.
+    pub fn parse_to_model(input: &str) -> Result<Model> {
+        match input {
+            "gpt-3.5-turbo" => Ok(Model::Gpt35Turbo),
+            "gpt-3.5-turbo-0613" => Ok(Model::Gpt35Turbo0613),
+            "gpt-3.5-turbo-16k" => Ok(Model::Gpt35Turbo16k),
+            "gpt-3.5-turbo-16k-0613" => Ok(Model::Gpt35Turbo16k0613),
+            "gpt-4" => Ok(Model::Gpt4),
+            "gpt-4-0613" => Ok(Model::Gpt40613),
+            "gpt-4-32k" => Ok(Model::Gpt432k),
+            "gpt-4-32k-0613" => Ok(Model::Gpt432k0613),
+            _ => Err(anyhow::anyhow!("Invalid model")),
+        }
+    }
 }
 
+/// Roles of the authors of messages.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) enum Role {
+pub enum Role {
+    /// Instructions to the assistant.
     System,
+    /// Replies of the model.
     Assistant,
+    /// Messages of the end user.
     User,
+    /// Results of function calls.
     Function,
 }
 
 impl Role {
-    pub(crate) fn parse_to_string(&self) -> Result<String> {
+    /// Name of the role in the API like "user".
+    pub fn parse_to_string(&self) -> Result<String> {
         match self {
             Role::System => Ok("system".to_string()),
             Role::Assistant => Ok("assistant".to_string()),
@@ -60,54 +77,197 @@ impl Role {
             Role::Function => Ok("function".to_string()),
         }
     }
-
-    // pub(crate) fn parse_to_role(input: &str) -> Result<Role> {
-    //     match input {
-    //         "system" => Ok(Role::System),
-    //         "assistant" => Ok(Role::Assistant),
-    //         "user" => Ok(Role::User),
-    //         "function" => Ok(Role::Function),
-    //         _ => Err(anyhow!("Invalid role")),
-    //     }
-    // }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct Options {
-    pub(crate) model: String,
-    pub(crate) messages: Vec<Message>,
+/// Request body of the chat completions API, where the parameters not set are left to the API.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Options {
+    /// ID of the model like "gpt-3.5-turbo".
+    pub model: String,
+    /// Messages of the conversation so far.
+    pub messages: Vec<Message>,
+    /// Functions the model may call.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) functions: Option<Vec<Function>>,
+    pub functions: Option<Vec<Function>>,
+    /// Whether and which function the model calls.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) function_call: Option<FunctionCallingSpecification>,
+    pub function_call: Option<FunctionCallingSpecification>,
+    /// Sampling temperature between 0 and 2, where higher is more random.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) temperature: Option<f64>,
+    pub temperature: Option<f64>,
+    /// Probability mass of the tokens considered by nucleus sampling.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) top_p: Option<f64>,
+    pub top_p: Option<f64>,
+    /// Number of choices to generate.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) n: Option<u64>,
+    pub n: Option<u64>,
+    /// Whether to stream the deltas as server-sent events.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) stream: Option<bool>,
+    pub stream: Option<bool>,
+    /// Sequences where the API stops generating.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) stop: Option<Vec<String>>,
+    pub stop: Option<Vec<String>>,
+    /// Maximum number of tokens to generate.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) max_tokens: Option<u64>,
+    pub max_tokens: Option<u64>,
+    /// Penalty between -2 and 2 of the tokens that have appeared so far.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) presence_penalty: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    /// Penalty between -2 and 2 of the tokens by their frequency so far.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) frequency_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    /// Bias between -100 and 100 added to the logits of the token IDs.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) logit_bias: Option<HashMap<String, f64>>,
+    pub logit_bias: Option<HashMap<String, f64>>,
+    /// ID of the end user to monitor abuse.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) user: Option<String>,
+    pub user: Option<String>,
+}
+
+impl Options {
+    /// Options of the model and the messages with the defaults of the API for the others.
+    pub fn new(model: String, messages: Vec<Message>) -> Self {
+        Self {
+            model,
+            messages,
+            functions: None,
+            function_call: None,
+            temperature: None,
+            top_p: None,
+            n: None,
+            stream: None,
+            stop: None,
+            max_tokens: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            user: None,
+        }
+    }
+
+    /// Sets the functions the model may call.
+    pub fn functions(mut self, functions: Vec<Function>) -> Self {
+        self.functions = Some(functions);
+        self
+    }
+
+    /// Sets whether and which function the model calls.
+    pub fn function_call(mut self, function_call: FunctionCallingSpecification) -> Self {
+        self.function_call = Some(function_call);
+        self
+    }
+
+    /// Sets the sampling temperature.
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// Sets the probability mass of nucleus sampling.
+    pub fn top_p(mut self, top_p: f64) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    /// Sets the number of choices.
+    pub fn n(mut self, n: u64) -> Self {
+        self.n = Some(n);
+        self
+    }
+
+    /// Sets whether to stream the deltas.
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    /// Sets the stop sequences.
+    pub fn stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    /// Sets the maximum number of tokens to generate.
+    pub fn max_tokens(mut self, max_tokens: u64) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sets the presence penalty.
+    pub fn presence_penalty(mut self, presence_penalty: f64) -> Self {
+        self.presence_penalty = Some(presence_penalty);
+        self
+    }
+
+    /// Sets the frequency penalty.
+    pub fn frequency_penalty(mut self, frequency_penalty: f64) -> Self {
+        self.frequency_penalty = Some(frequency_penalty);
+        self
+    }
+
+    /// Sets the bias of the token IDs.
+    pub fn logit_bias(mut self, logit_bias: HashMap<String, f64>) -> Self {
+        self.logit_bias = Some(logit_bias);
+        self
+    }
+
+    /// Sets the ID of the end user.
+    pub fn user(mut self, user: String) -> Self {
+        self.user = Some(user);
+        self
+    }
+}
+
+/// Sampling parameters of completions in TOML like:
+///
+/// ```toml
+/// [sampling]
+/// temperature = 0.7
+/// max_tokens = 256
+/// top_p = 0.9
+/// ```
+///
+/// Parameters not set are left to the defaults of the API.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Sampling {
+    /// Sampling temperature between 0 and 2.
+    pub temperature: Option<f64>,
+    /// Maximum number of tokens to generate.
+    pub max_tokens: Option<u64>,
+    /// Probability mass of nucleus sampling.
+    pub top_p: Option<f64>,
+}
+
+impl Sampling {
+    /// Parameters of this overriding the ones of the fallback.
+    pub fn or(self, fallback: Sampling) -> Self {
+        Self {
+            temperature: self.temperature.or(fallback.temperature),
+            max_tokens: self.max_tokens.or(fallback.max_tokens),
+            top_p: self.top_p.or(fallback.top_p),
+        }
+    }
+
+    /// Sets the parameters to the options.
+    pub fn apply(self, mut options: Options) -> Options {
+        options.temperature = self.temperature.or(options.temperature);
+        options.max_tokens = self.max_tokens.or(options.max_tokens);
+        options.top_p = self.top_p.or(options.top_p);
+        options
+    }
 }
 
+/// Function the model may call with the arguments generated in JSON.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct Function {
-    pub(crate) name: String,
+pub struct Function {
+    /// Name of the function called by the model.
+    pub name: String,
+    /// Description for the model to choose when and how to call the function.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) description: Option<String>,
-    pub(crate) parameters: serde_json::Map<String, serde_json::Value>,
+    pub description: Option<String>,
+    /// JSON Schema of the arguments.
+    pub parameters: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Clone for Function {
@@ -121,36 +281,68 @@ impl Clone for Function {
 }
 
 impl Function {
-    pub(crate) fn new(
+    /// Function of the JSON Schema of the arguments, which fails unless the schema is a JSON object.
+    pub fn new(
         name: String,
         description: Option<String>,
         parameters_schema: String,
-    ) -> Function {
-        Function {
+    ) -> Result<Function> {
+        Ok(Function {
             name,
             description,
-            parameters: serde_json::from_str(&parameters_schema).unwrap(),
-        }
+            parameters: serde_json::from_str(&parameters_schema)?,
+        })
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Whether and which function the model calls.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-pub(crate) enum FunctionCallingSpecification {
+pub enum FunctionCallingSpecification {
+    /// The model decides whether to call a function.
     Auto,
+    /// The model replies without calling a function.
     None,
+    /// The model calls the function of the name.
     Name(String),
 }
 
+/// Message of a conversation.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct Message {
-    pub(crate) role: String,
+pub struct Message {
+    /// Name of the role of the author like "user".
+    pub role: String,
+    /// Content of the message, which is none for a function call.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) content: Option<String>,
+    pub content: Option<String>,
+    /// Name of the author, or of the function of which result is the content.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) name: Option<String>,
+    pub name: Option<String>,
+    /// Function called by the assistant.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) function_call: Option<FunctionCall>,
+    pub function_call: Option<FunctionCall>,
+}
+
+impl Message {
+    /// Message of the content without a name or a function call.
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role: role.parse_to_string().unwrap(),
+            content: Some(content.into()),
+            name: None,
+            function_call: None,
+        }
+    }
+
+    /// Message of the assistant calling the function instead of replying.
+    pub fn function_call(function_call: FunctionCall) -> Self {
+        Self {
+            role: Role::Assistant.parse_to_string().unwrap(),
+            content: None,
+            name: None,
+            function_call: Some(function_call),
+        }
+    }
 }
 
 impl Clone for Message {
@@ -164,27 +356,41 @@ impl Clone for Message {
     }
 }
 
+/// Response body of the chat completions API.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct CompletionResult {
-    pub(crate) id: String,
-    pub(crate) object: String,
-    pub(crate) created: u64,
-    pub(crate) model: String,
-    pub(crate) choices: Vec<Choice>,
-    pub(crate) usage: Usage,
+pub struct CompletionResult {
+    /// ID of the completion like "chatcmpl-...".
+    pub id: String,
+    /// Type of the object, always "chat.completion".
+    pub object: String,
+    /// Unix timestamp in seconds of the creation.
+    pub created: u64,
+    /// ID of the model which generated the completion.
+    pub model: String,
+    /// Choices ordered by the index.
+    pub choices: Vec<Choice>,
+    /// Tokens used by the request.
+    pub usage: Usage,
 }
 
+/// Choice of a completion.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct Choice {
-    pub(crate) index: u64,
-    pub(crate) message: Message,
-    pub(crate) finish_reason: String,
+pub struct Choice {
+    /// Index of the choice in the choices.
+    pub index: u64,
+    /// Message generated by the assistant.
+    pub message: Message,
+    /// Why the generation stopped like "stop", "length" or "function_call".
+    pub finish_reason: String,
 }
 
+/// Call of a function by the assistant.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct FunctionCall {
-    pub(crate) name: String,
-    pub(crate) arguments: String,
+pub struct FunctionCall {
+    /// Name of the function to call.
+    pub name: String,
+    /// Arguments in JSON generated by the model, which may be invalid.
+    pub arguments: String,
 }
 
 impl Clone for FunctionCall {
@@ -196,33 +402,91 @@ impl Clone for FunctionCall {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct Usage {
-    pub(crate) prompt_tokens: u64,
-    pub(crate) completion_tokens: u64,
-    pub(crate) total_tokens: u64,
+/// Tokens used by a request.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Usage {
+    /// Tokens of the messages of the request.
+    pub prompt_tokens: u64,
+    /// Tokens generated in all choices.
+    pub completion_tokens: u64,
+    /// Sum of the prompt and the completion tokens.
+    pub total_tokens: u64,
 }
 
+/// Chunk of a streamed completion in a server-sent event.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct CompletionStreamingChunk {
-    pub(crate) id: String,
-    pub(crate) object: String,
-    pub(crate) created: u64,
-    pub(crate) model: String,
-    pub(crate) choices: Vec<ChoiceChunk>,
+pub struct CompletionStreamingChunk {
+    /// ID of the completion shared by the chunks.
+    pub id: String,
+    /// Type of the object, always "chat.completion.chunk".
+    pub object: String,
+    /// Unix timestamp in seconds of the creation.
+    pub created: u64,
+    /// ID of the model which generated the completion.
+    pub model: String,
+    /// Deltas of the choices in the chunk.
+    pub choices: Vec<ChoiceChunk>,
 }
 
+/// Delta of a choice in a chunk.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct ChoiceChunk {
-    pub(crate) delta: Delta,
-    pub(crate) index: u64,
-    pub(crate) finish_reason: Option<String>,
+pub struct ChoiceChunk {
+    /// Part of the message following the previous chunks.
+    pub delta: Delta,
+    /// Index of the choice in the choices.
+    pub index: u64,
+    /// Why the generation stopped, only in the last chunk of the choice.
+    pub finish_reason: Option<String>,
 }
 
+/// Part of a message in a chunk.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct Delta {
+pub struct Delta {
+    /// Role of the author, only in the first chunk of the choice.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) role: Option<String>,
+    pub role: Option<String>,
+    /// Text following the one of the previous chunks.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) content: Option<String>,
+    pub content: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling_overrides_the_fallback_for_each_parameter() {
+        let character = Sampling {
+            temperature: Some(1.2),
+            ..Default::default()
+        };
+        let server = Sampling {
+            temperature: Some(0.7),
+            max_tokens: Some(256),
+            top_p: None,
+        };
+
+        assert_eq!(
+            character.or(server),
+            Sampling {
+                temperature: Some(1.2),
+                max_tokens: Some(256),
+                top_p: None,
+            }
+        );
+    }
+
+    #[test]
+    fn sampling_is_applied_to_the_options() {
+        let sampling = Sampling {
+            temperature: Some(0.7),
+            max_tokens: None,
+            top_p: Some(0.9),
+        };
+        let options = sampling.apply(Options::new("gpt-4".to_string(), Vec::new()).max_tokens(64));
+
+        assert_eq!(options.temperature, Some(0.7));
+        assert_eq!(options.max_tokens, Some(64));
+        assert_eq!(options.top_p, Some(0.9));
+    }
 }
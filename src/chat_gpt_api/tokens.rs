@@ -0,0 +1,87 @@
+use crate::chat_gpt_api::specification::{Message, Usage};
+
+/// Tokens added by the API for each message.
+const TOKENS_PER_MESSAGE: u64 = 3;
+/// Tokens added by the API to prime the reply of the assistant.
+const TOKENS_PER_REPLY: u64 = 3;
+
+/// Estimates the number of tokens in the text without the tokenizer of the model.
+///
+/// A token is about four ASCII characters, and about one character in other scripts like Japanese.
+pub fn estimate_tokens(text: &str) -> u64 {
+    let ascii = text
+        .chars()
+        .filter(|character| character.is_ascii())
+        .count() as u64;
+    let others = text.chars().count() as u64 - ascii;
+
+    (ascii as f64 / 4.0).ceil() as u64 + others
+}
+
+/// Estimates the usage of a completion, which is not reported by the API in streaming.
+pub fn estimate_usage(messages: &[Message], completions: &[String]) -> Usage {
+    let prompt_tokens = messages
+        .iter()
+        .map(|message| {
+            let mut tokens = TOKENS_PER_MESSAGE + estimate_tokens(&message.role);
+            if let Some(content) = &message.content {
+                tokens += estimate_tokens(content);
+            }
+            if let Some(function_call) = &message.function_call {
+                tokens += estimate_tokens(&function_call.name);
+                tokens += estimate_tokens(&function_call.arguments);
+            }
+            tokens
+        })
+        .sum::<u64>()
+        + TOKENS_PER_REPLY;
+
+    let completion_tokens = completions
+        .iter()
+        .map(|completion| estimate_tokens(completion))
+        .sum::<u64>();
+
+    Usage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_gpt_api::specification::{FunctionCall, Role};
+
+    #[test]
+    fn ascii_is_estimated_per_four_characters() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+    }
+
+    #[test]
+    fn other_scripts_are_estimated_per_character() {
+        assert_eq!(estimate_tokens("こんにちは"), 5);
+        assert_eq!(estimate_tokens("Hi あ"), 2);
+    }
+
+    #[test]
+    fn usage_includes_the_overheads_of_messages() {
+        let messages = vec![
+            // 3 + "user" 1 + "Hello" 2
+            Message::new(Role::User, "Hello"),
+            // 3 + "assistant" 3 + the name 1 + the arguments 1
+            Message::function_call(FunctionCall {
+                name: "f".to_string(),
+                arguments: "{}".to_string(),
+            }),
+        ];
+
+        let usage = estimate_usage(&messages, &["Hi!".to_string(), "あい".to_string()]);
+
+        assert_eq!(usage.prompt_tokens, 6 + 8 + TOKENS_PER_REPLY);
+        assert_eq!(usage.completion_tokens, 3);
+        assert_eq!(usage.total_tokens, 20);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::accounting::ledger::Price;
+use crate::auth::AuthConfig;
+use crate::chat_gpt_api::client::UpstreamConfig;
+use crate::chat_gpt_api::specification::Sampling;
+use crate::gateway::GatewayConfig;
+use crate::grpc_web::GrpcWebConfig;
+use crate::logging::LoggingConfig;
+use crate::quota::QuotaConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::telemetry::TelemetryConfig;
+use crate::turn::BargeInPolicy;
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+/// Configuration of the server loaded from the TOML file of `SERVER_CONFIG_PATH`.
+///
+/// Every field has a default, so the file and each of its fields are optional.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address of the gRPC server.
+    pub(crate) address: String,
+    /// Serves with the certificate of `SERVER_CERT_PATH` and `SERVER_KEY_PATH`, in plaintext if false.
+    pub(crate) tls: bool,
+    pub(crate) barge_in: BargeInPolicy,
+    /// Maximum number of follow-up completions to continue an answer cut off by `max_tokens`.
+    pub(crate) max_continuations: u32,
+    /// Directory of the TOML files of the character profiles, only the default character if not set.
+    pub(crate) character_profiles_directory: Option<String>,
+    /// Sampling of the completions in the sessions, overridden by the characters.
+    pub(crate) sampling: Sampling,
+    /// Prices per model ID like "gpt-4" overriding the default ones.
+    pub(crate) prices: HashMap<String, Price>,
+    /// Interval of the log line of the accounting, disabled by zero.
+    pub(crate) accounting_log_interval_secs: u64,
+    pub(crate) quotas: QuotaConfig,
+    /// Directory to persist the state across restarts, nothing is persisted if not set.
+    pub(crate) persistence_directory: Option<String>,
+    pub(crate) rate_limits: RateLimitConfig,
+    pub(crate) auth: AuthConfig,
+    pub(crate) logging: LoggingConfig,
+    pub(crate) telemetry: TelemetryConfig,
+    /// Interval of the health check of the upstream API, disabled by zero.
+    pub(crate) upstream_check_interval_secs: u64,
+    /// Deadline to drain the calls in progress including streams on shutdown.
+    pub(crate) shutdown_grace_period_secs: u64,
+    pub(crate) gateway: GatewayConfig,
+    pub(crate) grpc_web: GrpcWebConfig,
+    pub(crate) upstream: UpstreamConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0:8000".to_string(),
+            tls: true,
+            barge_in: BargeInPolicy::default(),
+            max_continuations: 3,
+            character_profiles_directory: None,
+            sampling: Sampling::default(),
+            prices: HashMap::new(),
+            accounting_log_interval_secs: 300,
+            quotas: QuotaConfig::default(),
+            persistence_directory: None,
+            rate_limits: RateLimitConfig::default(),
+            auth: AuthConfig::default(),
+            logging: LoggingConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            upstream_check_interval_secs: 30,
+            shutdown_grace_period_secs: 30,
+            gateway: GatewayConfig::default(),
+            grpc_web: GrpcWebConfig::default(),
+            upstream: UpstreamConfig::default(),
+        }
+    }
+}
+
+pub(crate) fn load_server_config() -> Result<ServerConfig> {
+    let Ok(path) = env::var("SERVER_CONFIG_PATH") else {
+        return Ok(ServerConfig::default());
+    };
+
+    let text = fs::read_to_string(&path)?;
+    let config = toml::from_str::<ServerConfig>(&text)
+        .map_err(|error| anyhow::anyhow!("Invalid server config {:?}: {}", path, error))?;
+
+    Ok(config)
+}
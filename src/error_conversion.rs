@@ -1,6 +1,11 @@
 use tonic::{Code, Status};
 
 pub(crate) fn map_anyhow_error_to_grpc_status(error: anyhow::Error) -> Status {
+    // Status passed through anyhow::Error as it is
+    if let Some(status) = error.downcast_ref::<Status>() {
+        return status.clone();
+    }
+
     if let Some(hyper_error) = error.downcast_ref::<hyper::Error>() {
         if hyper_error.is_parse() {
             return Status::new(Code::Internal, "parse error");
@@ -0,0 +1,488 @@
+use crate::auth::Authenticator;
+use crate::chat::my_chat::chat_rpc::chat_server::{Chat, ChatServer};
+use crate::chat::my_chat::MyChat;
+use crate::chat_gpt_api::specification::Options;
+use crate::identity::ClientIdentity;
+use crate::openai_compat::{chat_request, completion_result, error_body, ChunkEncoder};
+use crate::rate_limit::{RateLimitLayer, StreamPermit};
+use crate::shutdown::ShutdownWatch;
+use crate::speak::my_speak::speak_rpc::speak_server::{Speak, SpeakServer};
+use crate::speak::my_speak::speak_rpc::{Cry, Emotion, Motion, SpeakReaction};
+use crate::speak::my_speak::MySpeak;
+use crate::websocket::upgrade;
+use anyhow::Result;
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use http_body::{LengthLimitError, Limited};
+use hyper::header::{
+    HeaderMap, HeaderValue, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, RETRY_AFTER,
+};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Server, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::metadata::MetadataMap;
+use tonic::server::NamedService;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tonic::{Code, Status};
+use tower::ServiceBuilder;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// REST gateway configured like:
+///
+/// ```toml
+/// [gateway]
+/// address = "127.0.0.1:8080"
+/// allowed_origins = ["https://example.com"]
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct GatewayConfig {
+    /// Address of the plaintext HTTP server, disabled if not set.
+    /// Put it behind a proxy terminating TLS to expose it.
+    pub(crate) address: Option<String>,
+    /// Origins allowed by CORS, "*" for any origin and only the same origin if empty.
+    pub(crate) allowed_origins: Vec<String>,
+    /// Limit of the request bodies, larger ones are answered with 413.
+    pub(crate) max_body_bytes: usize,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            allowed_origins: Vec::new(),
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Metadata of the HTTP status of an error overriding the one mapped from the code.
+pub(crate) const HTTP_STATUS_KEY: &str = "http-status";
+
+/// HTTP/JSON gateway to the chat and speak services sharing the sessions with gRPC.
+///
+/// Requests are authenticated and rate limited as the corresponding gRPC methods.
+pub(crate) struct Gateway {
+    pub(crate) chat: Arc<MyChat>,
+    pub(crate) speak: Arc<MySpeak>,
+    pub(crate) authenticator: Arc<Authenticator>,
+    pub(crate) rate_limit: RateLimitLayer,
+    pub(crate) max_body_bytes: usize,
+    /// Held until the gateway and the WebSocket connections have finished.
+    pub(crate) shutdown: ShutdownWatch,
+}
+
+pub(crate) async fn serve_gateway(
+    address: SocketAddr,
+    config: &GatewayConfig,
+    gateway: Gateway,
+) -> Result<()> {
+    let cors = cors_layer(&config.allowed_origins)?;
+    let mut shutdown = gateway.shutdown.clone();
+    let gateway = Arc::new(gateway);
+
+    let make_service = make_service_fn(move |connection: &AddrStream| {
+        let gateway = Arc::clone(&gateway);
+        let connect_info = connection.connect_info();
+        let service = ServiceBuilder::new()
+            .layer(cors.clone())
+            .service(service_fn(move |request| {
+                route(Arc::clone(&gateway), connect_info.clone(), request)
+            }));
+        async move { Ok::<_, Infallible>(service) }
+    });
+
+    Server::bind(&address)
+        .serve(make_service)
+        .with_graceful_shutdown(async move { shutdown.stopped().await })
+        .await?;
+
+    Ok(())
+}
+
+fn cors_layer(allowed_origins: &[String]) -> Result<CorsLayer> {
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin(allowed_origins)?)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+        .expose_headers([RETRY_AFTER]))
+}
+
+/// Origins allowed by CORS, where "*" allows any origin.
+pub(crate) fn allow_origin(allowed_origins: &[String]) -> Result<AllowOrigin> {
+    if allowed_origins.iter().any(|origin| origin == "*") {
+        return Ok(AllowOrigin::any());
+    }
+
+    let origins = allowed_origins
+        .iter()
+        .map(|origin| origin.parse::<HeaderValue>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(AllowOrigin::list(origins))
+}
+
+async fn route(
+    gateway: Arc<Gateway>,
+    connect_info: TcpConnectInfo,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let result = match (request.method(), request.uri().path()) {
+        // curl -d '{ "message": "Hello!" }' localhost:8080/v1/chat
+        (&Method::POST, "/v1/chat") => gateway.chat(connect_info, request).await,
+        // curl -N -d '{ "message": "Hello!" }' localhost:8080/v1/chat/stream
+        (&Method::POST, "/v1/chat/stream") => gateway.chat_stream(connect_info, request).await,
+        // curl -d '{ "message": "おはよう!" }' localhost:8080/v1/speak
+        (&Method::POST, "/v1/speak") => gateway.speak(connect_info, request).await,
+        // websocat 'ws://localhost:8080/v1/ws?session_id=alice'
+        (&Method::GET, "/v1/ws") => upgrade(Arc::clone(&gateway), connect_info, request).await,
+        // curl -d '{ "model": "gpt-3.5-turbo", "messages": [{ "role": "user", "content": "Hello!" }] }' localhost:8080/v1/chat/completions
+        (&Method::POST, "/v1/chat/completions") => {
+            // Errors are in the format of the OpenAI API
+            return Ok(gateway
+                .chat_completions(connect_info, request)
+                .await
+                .unwrap_or_else(openai_error_response));
+        }
+        (_, path) => Err(Status::new(
+            Code::NotFound,
+            format!("No route for {}", path),
+        )),
+    };
+
+    Ok(result.unwrap_or_else(error_response))
+}
+
+impl Gateway {
+    async fn chat(
+        &self,
+        connect_info: TcpConnectInfo,
+        request: hyper::Request<Body>,
+    ) -> Result<hyper::Response<Body>, Status> {
+        let (request, _permit) = self
+            .grpc_request(
+                ChatServer::<MyChat>::NAME,
+                "/chat.Chat/CompleteChat",
+                connect_info,
+                request,
+            )
+            .await?;
+
+        let response = self.chat.complete_chat(request).await?;
+
+        Ok(json_response(&response.into_inner()))
+    }
+
+    /// Streams the completion as server-sent events of which data are the streaming responses,
+    /// where an error is sent as the "error" event.
+    async fn chat_stream(
+        &self,
+        connect_info: TcpConnectInfo,
+        request: hyper::Request<Body>,
+    ) -> Result<hyper::Response<Body>, Status> {
+        let (request, permit) = self
+            .grpc_request(
+                ChatServer::<MyChat>::NAME,
+                "/chat.Chat/CompleteChatStreaming",
+                connect_info,
+                request,
+            )
+            .await?;
+
+        let stream = self
+            .chat
+            .complete_chat_streaming(request)
+            .await?
+            .into_inner();
+
+        // Hold the permit until the stream ends
+        let events = stream.map(move |response| {
+            let _permit = &permit;
+            let event = match response {
+                Ok(response) => format!("data: {}\n\n", to_json(&response)),
+                Err(status) => format!("event: error\ndata: {}\n\n", error_json(&status)),
+            };
+            Ok::<_, Infallible>(event)
+        });
+
+        Ok(event_stream_response(Body::wrap_stream(events)))
+    }
+
+    async fn speak(
+        &self,
+        connect_info: TcpConnectInfo,
+        request: hyper::Request<Body>,
+    ) -> Result<hyper::Response<Body>, Status> {
+        let (request, _permit) = self
+            .grpc_request(
+                SpeakServer::<MySpeak>::NAME,
+                "/speak.Speak/SpeakTo",
+                connect_info,
+                request,
+            )
+            .await?;
+
+        let reaction = self.speak.speak_to(request).await?.into_inner();
+
+        Ok(json_response(&reaction_json(&reaction)))
+    }
+
+    /// Chat completions of the OpenAI API through the chat of the agent.
+    async fn chat_completions(
+        &self,
+        connect_info: TcpConnectInfo,
+        request: hyper::Request<Body>,
+    ) -> Result<hyper::Response<Body>, Status> {
+        let (headers, options) = read_json::<Options>(request, self.max_body_bytes).await?;
+        let chat_request = chat_request(&headers, &options)?;
+
+        if options.stream != Some(true) {
+            let (request, _permit) = self.authorize(
+                ChatServer::<MyChat>::NAME,
+                "/chat.Chat/CompleteChat",
+                connect_info,
+                headers,
+                chat_request,
+            )?;
+            let response = self.chat.complete_chat(request).await?.into_inner();
+            return Ok(json_response(&completion_result(response)));
+        }
+
+        let (request, permit) = self.authorize(
+            ChatServer::<MyChat>::NAME,
+            "/chat.Chat/CompleteChatStreaming",
+            connect_info,
+            headers,
+            chat_request,
+        )?;
+        let model = self
+            .chat
+            .state
+            .lock()
+            .await
+            .model
+            .parse_to_string()
+            .unwrap();
+        let stream = self
+            .chat
+            .complete_chat_streaming(request)
+            .await?
+            .into_inner();
+
+        // Hold the permit until the stream ends
+        let mut encoder = ChunkEncoder::new(model);
+        let events = stream
+            .filter_map(move |response| {
+                let _permit = &permit;
+                let event = match response {
+                    Ok(response) => encoder
+                        .encode(response)
+                        .map(|chunk| format!("data: {}\n\n", to_json(&chunk))),
+                    Err(status) => Some(format!("data: {}\n\n", to_json(&error_body(&status)))),
+                };
+                future::ready(event)
+            })
+            .chain(stream::once(future::ready("data: [DONE]\n\n".to_string())))
+            .map(Ok::<_, Infallible>);
+
+        Ok(event_stream_response(Body::wrap_stream(events)))
+    }
+
+    /// Converts the HTTP request to the one of the gRPC method with the identity of the client.
+    async fn grpc_request<T: DeserializeOwned>(
+        &self,
+        service: &str,
+        method: &str,
+        connect_info: TcpConnectInfo,
+        request: hyper::Request<Body>,
+    ) -> Result<(tonic::Request<T>, Option<StreamPermit>), Status> {
+        let (headers, message) = read_json::<T>(request, self.max_body_bytes).await?;
+        self.authorize(service, method, connect_info, headers, message)
+    }
+
+    /// Attaches the identity of the client to the message and takes a slot of the method.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn authorize<T>(
+        &self,
+        service: &str,
+        method: &str,
+        connect_info: TcpConnectInfo,
+        headers: HeaderMap,
+        message: T,
+    ) -> Result<(tonic::Request<T>, Option<StreamPermit>), Status> {
+        let mut request = request_with_metadata(connect_info, headers, message);
+
+        self.rate_limit.acquire_peer(request.remote_addr())?;
+        let identity = self.authenticator.identify(service, &request)?;
+        let permit = self.rate_limit.acquire(&identity.name, method)?;
+        request.extensions_mut().insert(identity);
+
+        Ok((request, permit))
+    }
+
+    /// Identifies the client allowed for any of the services after the rate limit of the peer.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn identify(
+        &self,
+        services: &[&str],
+        connect_info: TcpConnectInfo,
+        headers: HeaderMap,
+    ) -> Result<ClientIdentity, Status> {
+        let request = request_with_metadata(connect_info, headers, ());
+
+        self.rate_limit.acquire_peer(request.remote_addr())?;
+        let mut result = Err(Status::new(
+            Code::PermissionDenied,
+            "No service is allowed".to_string(),
+        ));
+        for service in services {
+            result = self.authenticator.identify(service, &request);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+}
+
+/// gRPC request of the message with the headers as the metadata.
+fn request_with_metadata<T>(
+    connect_info: TcpConnectInfo,
+    headers: HeaderMap,
+    message: T,
+) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    *request.metadata_mut() = MetadataMap::from_headers(headers);
+    request.extensions_mut().insert(connect_info);
+    request
+}
+
+async fn read_json<T: DeserializeOwned>(
+    request: hyper::Request<Body>,
+    max_bytes: usize,
+) -> Result<(HeaderMap, T), Status> {
+    let (parts, body) = request.into_parts();
+
+    let bytes = hyper::body::to_bytes(Limited::new(body, max_bytes))
+        .await
+        .map_err(|error| {
+            if error.downcast_ref::<LengthLimitError>().is_none() {
+                return Status::new(
+                    Code::InvalidArgument,
+                    format!("Failed to read body: {}", error),
+                );
+            }
+
+            let mut metadata = MetadataMap::new();
+            metadata.insert(
+                HTTP_STATUS_KEY,
+                StatusCode::PAYLOAD_TOO_LARGE.as_str().parse().unwrap(),
+            );
+            Status::with_metadata(
+                Code::InvalidArgument,
+                format!("Body exceeds {} bytes", max_bytes),
+                metadata,
+            )
+        })?;
+    let message = serde_json::from_slice::<T>(&bytes)
+        .map_err(|error| Status::new(Code::InvalidArgument, format!("Invalid JSON: {}", error)))?;
+
+    Ok((parts.headers, message))
+}
+
+/// Reaction with the names of the enum values as the JSON mapping of protobuf.
+pub(crate) fn reaction_json(reaction: &SpeakReaction) -> serde_json::Value {
+    let name = |value: Option<&'static str>| value.unwrap_or_default();
+
+    serde_json::json!({
+        "emotion": name(Emotion::from_i32(reaction.emotion).map(|emotion| emotion.as_str_name())),
+        "motion": name(Motion::from_i32(reaction.motion).map(|motion| motion.as_str_name())),
+        "cry": name(Cry::from_i32(reaction.cry).map(|cry| cry.as_str_name())),
+    })
+}
+
+pub(crate) fn to_json<T: Serialize>(value: &T) -> String {
+    // Messages consist of serializable fields only
+    serde_json::to_string(value).unwrap()
+}
+
+fn json_response<T: Serialize>(value: &T) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::from(to_json(value)));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn event_stream_response(body: Body) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    response
+}
+
+fn error_json(status: &Status) -> String {
+    to_json(&serde_json::json!({
+        "code": format!("{:?}", status.code()),
+        "message": status.message(),
+    }))
+}
+
+fn error_response(status: Status) -> hyper::Response<Body> {
+    status_response(&status, error_json(&status))
+}
+
+fn openai_error_response(status: Status) -> hyper::Response<Body> {
+    status_response(&status, to_json(&error_body(&status)))
+}
+
+fn status_response(status: &Status, body: String) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::from(body));
+    *response.status_mut() = status
+        .metadata()
+        .get(HTTP_STATUS_KEY)
+        .and_then(|value| StatusCode::from_bytes(value.as_bytes()).ok())
+        .unwrap_or_else(|| http_status(status.code()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    // Set by the rate limit
+    let retry_after_ms = status
+        .metadata()
+        .get("retry-after-ms")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if let Some(retry_after_ms) = retry_after_ms {
+        let secs = (retry_after_ms as f64 / 1000.0).ceil() as u64;
+        response.headers_mut().insert(RETRY_AFTER, secs.into());
+    }
+
+    response
+}
+
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::OutOfRange | Code::FailedPrecondition => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Cancelled | Code::Unknown | Code::Internal | Code::DataLoss => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use crate::gateway::allow_origin;
+use anyhow::Result;
+use serde::Deserialize;
+use std::time::Duration;
+use tonic::codegen::http::header::{HeaderName, AUTHORIZATION, CONTENT_TYPE};
+use tonic::codegen::http::Method;
+use tower_http::cors::CorsLayer;
+
+/// Max age of the CORS preflight of browsers.
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// gRPC-Web for browser clients configured like:
+///
+/// ```toml
+/// [grpc_web]
+/// allowed_origins = ["https://viewer.example.com"]
+/// ```
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub(crate) struct GrpcWebConfig {
+    /// Origins allowed by CORS, "*" for any origin and only the same origin if empty.
+    pub(crate) allowed_origins: Vec<String>,
+}
+
+/// CORS of gRPC-Web calls exposing the status and the metadata of the errors.
+pub(crate) fn grpc_web_cors(config: &GrpcWebConfig) -> Result<CorsLayer> {
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin(&config.allowed_origins)?)
+        .allow_methods([Method::POST])
+        .allow_headers([
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            HeaderName::from_static("x-grpc-web"),
+            HeaderName::from_static("x-user-agent"),
+            HeaderName::from_static("grpc-timeout"),
+        ])
+        .expose_headers([
+            HeaderName::from_static("grpc-status"),
+            HeaderName::from_static("grpc-message"),
+            HeaderName::from_static("grpc-status-details-bin"),
+            HeaderName::from_static("retry-after-ms"),
+        ])
+        .max_age(PREFLIGHT_MAX_AGE))
+}
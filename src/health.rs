@@ -0,0 +1,192 @@
+use crate::accounting::my_accounting::accounting_rpc::accounting_server::AccountingServer;
+use crate::accounting::my_accounting::MyAccounting;
+use crate::admin::my_admin::admin_rpc::admin_server::AdminServer;
+use crate::admin::my_admin::MyAdmin;
+use crate::chat::my_chat::chat_rpc::chat_server::ChatServer;
+use crate::chat::my_chat::MyChat;
+use crate::chat_gpt_api::client::Upstream;
+use crate::speak::my_speak::speak_rpc::speak_server::SpeakServer;
+use crate::speak::my_speak::MySpeak;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::server::NamedService;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+/// Services depending on the upstream API.
+const UPSTREAM_SERVICES: [&str; 2] = [ChatServer::<MyChat>::NAME, SpeakServer::<MySpeak>::NAME];
+
+const SERVICES: [&str; 4] = [
+    ChatServer::<MyChat>::NAME,
+    SpeakServer::<MySpeak>::NAME,
+    AccountingServer::<MyAccounting>::NAME,
+    AdminServer::<MyAdmin>::NAME,
+];
+
+/// Reports all services as serving.
+pub(crate) async fn report_serving(reporter: &mut HealthReporter) {
+    for service in SERVICES {
+        reporter
+            .set_service_status(service, ServingStatus::Serving)
+            .await;
+    }
+}
+
+/// Reports the server and all services as not serving to stop receiving new requests.
+pub(crate) async fn report_not_serving(reporter: &mut HealthReporter) {
+    reporter
+        .set_service_status("", ServingStatus::NotServing)
+        .await;
+    for service in SERVICES {
+        reporter
+            .set_service_status(service, ServingStatus::NotServing)
+            .await;
+    }
+}
+
+/// Reports the services depending on the upstream API by checking it periodically.
+pub(crate) async fn watch_upstream(
+    mut reporter: HealthReporter,
+    upstream: Arc<Upstream>,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
+    let mut reachable = true;
+
+    loop {
+        interval.tick().await;
+
+        let result = upstream.check_reachability().await;
+        if result.is_ok() == reachable {
+            continue;
+        }
+        reachable = result.is_ok();
+
+        let status = match result {
+            Ok(_) => {
+                tracing::info!("Upstream API is reachable again");
+                ServingStatus::Serving
+            }
+            Err(error) => {
+                tracing::warn!(%error, "Upstream API is not reachable");
+                ServingStatus::NotServing
+            }
+        };
+        for service in UPSTREAM_SERVICES {
+            reporter.set_service_status(service, status).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_gpt_api::client::UpstreamConfig;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Channel;
+    use tonic_health::pb::health_check_response;
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::HealthCheckRequest;
+
+    /// Upstream API answering the models with 503 Service Unavailable while not available.
+    fn upstream(available: Arc<AtomicBool>) -> SocketAddr {
+        let server = hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            make_service_fn(move |_| {
+                let available = Arc::clone(&available);
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_| {
+                        let status = if available.load(Ordering::SeqCst) {
+                            StatusCode::OK
+                        } else {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        };
+                        async move {
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(status)
+                                    .body(Body::from("{}"))
+                                    .unwrap(),
+                            )
+                        }
+                    }))
+                }
+            }),
+        );
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+
+    /// Waits for the status of the service, which is checked a few times a second.
+    async fn wait_for(
+        client: &mut HealthClient<Channel>,
+        service: &str,
+        expected: health_check_response::ServingStatus,
+    ) -> bool {
+        for _ in 0..40 {
+            let response = client
+                .check(HealthCheckRequest {
+                    service: service.to_string(),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            if response.status() == expected {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn upstream_services_follow_the_upstream() {
+        use health_check_response::ServingStatus::{NotServing, Serving};
+
+        let available = Arc::new(AtomicBool::new(true));
+        let upstream = Upstream::new(UpstreamConfig {
+            base_url: format!("http://{}/v1", upstream(Arc::clone(&available))),
+            api_key: Some("sk-test".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (mut reporter, health_server) = tonic_health::server::health_reporter();
+        report_serving(&mut reporter).await;
+        tokio::spawn(watch_upstream(
+            reporter,
+            Arc::new(upstream),
+            Duration::from_millis(50),
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(health_server)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let channel = Channel::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(channel);
+
+        let chat = ChatServer::<MyChat>::NAME;
+        assert!(wait_for(&mut client, chat, Serving).await);
+
+        available.store(false, Ordering::SeqCst);
+        assert!(wait_for(&mut client, chat, NotServing).await);
+        assert!(wait_for(&mut client, SpeakServer::<MySpeak>::NAME, NotServing).await);
+        // The services not calling the upstream API are still serving
+        assert!(wait_for(&mut client, AdminServer::<MyAdmin>::NAME, Serving).await);
+
+        available.store(true, Ordering::SeqCst);
+        assert!(wait_for(&mut client, chat, Serving).await);
+    }
+}
@@ -0,0 +1,63 @@
+use serde::Deserialize;
+use tonic::{Code, Request, Status};
+
+/// Role of a client, where a role has all permissions of the lower ones.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Role {
+    /// Chats and speaks in sessions.
+    #[default]
+    User,
+    /// Reads the settings, the sessions and the usage.
+    Operator,
+    /// Changes the settings and reads the transcripts of sessions.
+    Admin,
+}
+
+/// Identity of the client attached to the request by the authentication.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientIdentity {
+    pub(crate) name: String,
+    pub(crate) role: Role,
+}
+
+/// Identity of the client of the request,
+/// which is the remote IP address unless the client is authenticated.
+pub(crate) fn client_identity<T>(request: &Request<T>) -> String {
+    if let Some(identity) = request.extensions().get::<ClientIdentity>() {
+        return identity.name.clone();
+    }
+
+    match request.remote_addr() {
+        Some(address) => address.ip().to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Identity of the client of the request with the role,
+/// which is the remote IP address with the user role unless the client is authenticated.
+pub(crate) fn client_of<T>(request: &Request<T>) -> ClientIdentity {
+    match request.extensions().get::<ClientIdentity>() {
+        Some(identity) => identity.clone(),
+        None => ClientIdentity {
+            name: client_identity(request),
+            role: Role::User,
+        },
+    }
+}
+
+/// Checks that the client of the request has the role or a higher one.
+#[allow(clippy::result_large_err)]
+pub(crate) fn require_role<T>(request: &Request<T>, role: Role) -> Result<(), Status> {
+    match request.extensions().get::<ClientIdentity>() {
+        None => Err(Status::new(
+            Code::Unauthenticated,
+            "No identity of the client".to_string(),
+        )),
+        Some(identity) if identity.role < role => Err(Status::new(
+            Code::PermissionDenied,
+            format!("{} requires the {:?} role", identity.name, role),
+        )),
+        Some(_) => Ok(()),
+    }
+}
@@ -0,0 +1,96 @@
+//! Agent of LLM chats with a client of the OpenAI chat completions API.
+//!
+//! The client, the specification of the API and the memories of conversations are always available:
+//!
+//! ```no_run
+//! use llm_agent_prototype_rust::chat_gpt_api::client::{Upstream, UpstreamConfig};
+//! use llm_agent_prototype_rust::chat_gpt_api::specification::{Message, Options, Role};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! // The key is read from OPENAI_API_KEY unless it is set in the configuration
+//! let upstream = Upstream::new(UpstreamConfig::default())?;
+//! let options = Options::new(
+//!     "gpt-3.5-turbo".to_string(),
+//!     vec![Message::new(Role::User, "Hello!")],
+//! )
+//! .temperature(0.7)
+//! .max_tokens(256);
+//!
+//! let result = upstream.complete_chat(options).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The `server` feature, enabled by default, adds the gRPC server with the sessions and the characters
+//! of the agent and its gateways. The `cassettes` feature, enabled by the server, adds the recording
+//! and the replaying of the traffic to the upstream API to the client.
+
+#![warn(missing_docs)]
+
+#[cfg(feature = "server")]
+mod accounting;
+#[cfg(feature = "server")]
+mod admin;
+#[cfg(feature = "server")]
+mod api_state;
+#[cfg(feature = "server")]
+mod auth;
+#[cfg(feature = "server")]
+mod certification;
+#[cfg(feature = "server")]
+mod chat;
+/// Client of the chat completions API and the memories of conversations.
+pub mod chat_gpt_api;
+#[cfg(feature = "server")]
+mod config;
+#[cfg(feature = "server")]
+mod error_conversion;
+#[cfg(feature = "server")]
+mod gateway;
+#[cfg(feature = "server")]
+mod grpc_web;
+#[cfg(feature = "server")]
+mod health;
+#[cfg(feature = "server")]
+mod identity;
+#[cfg(feature = "server")]
+mod logging;
+#[cfg(feature = "server")]
+mod metrics;
+#[cfg(feature = "server")]
+mod openai_compat;
+#[cfg(feature = "server")]
+mod persistence;
+#[cfg(feature = "server")]
+mod quota;
+#[cfg(feature = "server")]
+mod rate_limit;
+mod redaction;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(feature = "server")]
+mod session;
+#[cfg(feature = "server")]
+mod shutdown;
+#[cfg(feature = "server")]
+mod speak;
+#[cfg(feature = "server")]
+mod telemetry;
+#[cfg(feature = "server")]
+mod turn;
+#[cfg(feature = "server")]
+mod websocket;
+
+#[cfg(feature = "server")]
+pub use crate::config::ServerConfig;
+#[cfg(feature = "server")]
+pub use crate::server::{run, serve};
+
+/// Messages and clients of the gRPC services.
+#[cfg(feature = "server")]
+pub mod rpc {
+    pub use crate::accounting::my_accounting::accounting_rpc as accounting;
+    pub use crate::admin::my_admin::admin_rpc as admin;
+    pub use crate::chat::my_chat::chat_rpc as chat;
+    pub use crate::speak::my_speak::speak_rpc as speak;
+}
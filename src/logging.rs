@@ -0,0 +1,73 @@
+use crate::redaction::set_redacts_content;
+use anyhow::Result;
+use opentelemetry::sdk::trace::Tracer;
+use serde::Deserialize;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Logging configured like:
+///
+/// ```toml
+/// [logging]
+/// level = "info,llm_agent_prototype_rust=debug"
+/// format = "json"
+/// redact_content = false
+/// ```
+///
+/// `RUST_LOG` overrides the level if it is set.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct LoggingConfig {
+    /// Directives of `tracing_subscriber::EnvFilter`.
+    pub(crate) level: String,
+    pub(crate) format: LogFormat,
+    /// Whether to hide the content of messages in the logs.
+    pub(crate) redact_content: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::default(),
+            redact_content: true,
+        }
+    }
+}
+
+/// Installs the global subscriber, which logs spans with the latency when they close
+/// and exports them by the tracer if any.
+pub(crate) fn init_logging(config: &LoggingConfig, tracer: Option<Tracer>) -> Result<()> {
+    set_redacts_content(config.redact_content);
+
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.level)?,
+    };
+
+    let (text, json) = match config.format {
+        LogFormat::Text => (Some(fmt::layer().with_span_events(FmtSpan::CLOSE)), None),
+        LogFormat::Json => (
+            None,
+            Some(fmt::layer().json().with_span_events(FmtSpan::CLOSE)),
+        ),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .try_init()
+        .map_err(|error| anyhow::anyhow!("Failed to initialize logging: {}", error))
+}
@@ -0,0 +1,152 @@
+use once_cell::sync::Lazy;
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry};
+use std::time::Instant;
+
+pub(crate) mod exporter;
+
+pub(crate) static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// Metrics of the server exposed for Prometheus.
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// Calls per RPC method and gRPC status code.
+    pub(crate) grpc_requests: IntCounterVec,
+    /// Duration of calls per RPC method until the response including streams ends.
+    pub(crate) grpc_request_duration: HistogramVec,
+    upstream_request_duration: HistogramVec,
+    /// Failed requests to the API per model and HTTP status code, "connection" if not connected.
+    upstream_errors: IntCounterVec,
+    time_to_first_token: HistogramVec,
+    tokens_per_second: HistogramVec,
+    active_sessions: IntGauge,
+    /// Messages in the context memories of all sessions.
+    memory_messages: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let grpc_requests = IntCounterVec::new(
+            Opts::new("grpc_requests_total", "Number of gRPC calls"),
+            &["method", "code"],
+        )
+        .unwrap();
+        let grpc_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "grpc_request_duration_seconds",
+                "Duration of gRPC calls until the response ends",
+            ),
+            &["method"],
+        )
+        .unwrap();
+        let upstream_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "Duration of completions by the OpenAI API",
+            )
+            .buckets(vec![0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 40.0, 80.0]),
+            &["model", "stream"],
+        )
+        .unwrap();
+        let upstream_errors = IntCounterVec::new(
+            Opts::new(
+                "upstream_errors_total",
+                "Number of failed requests to the OpenAI API",
+            ),
+            &["model", "code"],
+        )
+        .unwrap();
+        let time_to_first_token = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_time_to_first_token_seconds",
+                "Time until the first token of streaming completions",
+            ),
+            &["model"],
+        )
+        .unwrap();
+        let tokens_per_second = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_tokens_per_second",
+                "Completion tokens generated per second",
+            )
+            .buckets(vec![5.0, 10.0, 20.0, 40.0, 80.0, 160.0]),
+            &["model"],
+        )
+        .unwrap();
+        let active_sessions = IntGauge::new("active_sessions", "Number of sessions").unwrap();
+        let memory_messages = IntGauge::new(
+            "context_memory_messages",
+            "Number of messages in the context memories of all sessions",
+        )
+        .unwrap();
+
+        registry.register(Box::new(grpc_requests.clone())).unwrap();
+        registry
+            .register(Box::new(grpc_request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_errors.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(time_to_first_token.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(tokens_per_second.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(active_sessions.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(memory_messages.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            grpc_requests,
+            grpc_request_duration,
+            upstream_request_duration,
+            upstream_errors,
+            time_to_first_token,
+            tokens_per_second,
+            active_sessions,
+            memory_messages,
+        }
+    }
+
+    /// Observes a completion finished successfully.
+    ///
+    /// The generation starts at the first token in streaming, or at the request otherwise.
+    pub(crate) fn observe_upstream(
+        &self,
+        model: &str,
+        stream: bool,
+        started_at: Instant,
+        generation_started_at: Instant,
+        completion_tokens: u64,
+    ) {
+        self.upstream_request_duration
+            .with_label_values(&[model, if stream { "true" } else { "false" }])
+            .observe(started_at.elapsed().as_secs_f64());
+
+        let generation_secs = generation_started_at.elapsed().as_secs_f64();
+        if generation_secs > 0.0 && completion_tokens > 0 {
+            self.tokens_per_second
+                .with_label_values(&[model])
+                .observe(completion_tokens as f64 / generation_secs);
+        }
+    }
+
+    pub(crate) fn observe_first_token(&self, model: &str, started_at: Instant) {
+        self.time_to_first_token
+            .with_label_values(&[model])
+            .observe(started_at.elapsed().as_secs_f64());
+    }
+
+    pub(crate) fn count_upstream_error(&self, model: &str, code: &str) {
+        self.upstream_errors.with_label_values(&[model, code]).inc();
+    }
+}
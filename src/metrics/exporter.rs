@@ -0,0 +1,74 @@
+use crate::api_state::ApiState;
+use crate::chat_gpt_api::memory::Memory;
+use crate::metrics::METRICS;
+use crate::shutdown::ShutdownWatch;
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use prometheus::{Encoder, TextEncoder};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Serves the metrics at `/metrics` over HTTP until the shutdown.
+pub(crate) async fn serve_metrics(
+    address: SocketAddr,
+    state: Arc<Mutex<ApiState>>,
+    mut shutdown: ShutdownWatch,
+) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let state = Arc::clone(&state);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                respond(Arc::clone(&state), request)
+            }))
+        }
+    });
+
+    Server::bind(&address)
+        .serve(make_service)
+        .with_graceful_shutdown(async move { shutdown.stopped().await })
+        .await?;
+
+    Ok(())
+}
+
+async fn respond(
+    state: Arc<Mutex<ApiState>>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    // Gauges of the state are sampled on scrape
+    {
+        let state = state.lock().await;
+        let messages: usize = state
+            .sessions
+            .values()
+            .map(|session| session.context_memory.get().len())
+            .sum();
+        METRICS.active_sessions.set(state.sessions.len() as i64);
+        METRICS.memory_messages.set(messages as i64);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(error) = encoder.encode(&METRICS.registry.gather(), &mut buffer) {
+        tracing::error!(%error, "Failed to encode metrics");
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(response);
+    }
+
+    let mut response = Response::new(Body::from(buffer));
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        encoder.format_type().parse().unwrap(),
+    );
+    Ok(response)
+}
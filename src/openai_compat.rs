@@ -0,0 +1,151 @@
+use crate::chat::my_chat::chat_rpc::{ChatRequest, ChatResponse, ChatStreamingResponse};
+use crate::chat_gpt_api::specification::{
+    Choice, ChoiceChunk, CompletionResult, CompletionStreamingChunk, Delta, Message, Options, Role,
+    Usage,
+};
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::codegen::http::HeaderMap;
+use tonic::{Code, Status};
+
+/// Header of the session of the agent, the `user` of the request is used if not set.
+pub(crate) const SESSION_HEADER: &str = "x-session-id";
+
+/// Converts the request of the OpenAI chat completions API to the chat of the agent.
+///
+/// Only the last user message is taken since the session memory holds the history,
+/// and the model, the prompt and the parameters of the agent are used instead of the requested ones
+/// except for `n` and `max_tokens`.
+#[allow(clippy::result_large_err)]
+pub(crate) fn chat_request(headers: &HeaderMap, options: &Options) -> Result<ChatRequest, Status> {
+    let user = Role::User.parse_to_string().unwrap();
+    let message = options
+        .messages
+        .iter()
+        .rev()
+        .find(|message| message.role == user)
+        .and_then(|message| message.content.clone())
+        .ok_or_else(|| Status::new(Code::InvalidArgument, "No user message".to_string()))?;
+
+    let session_id = headers
+        .get(SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+        .or_else(|| options.user.clone())
+        .unwrap_or_default();
+
+    Ok(ChatRequest {
+        message,
+        session_id,
+        n: options.n.unwrap_or(1) as u32,
+        continue_on_length: false,
+        max_tokens: options.max_tokens.unwrap_or(0) as u32,
+    })
+}
+
+pub(crate) fn completion_result(response: ChatResponse) -> CompletionResult {
+    let choices = response
+        .choices
+        .into_iter()
+        .map(|choice| Choice {
+            index: choice.index as u64,
+            message: Message::new(Role::Assistant, choice.content),
+            finish_reason: choice.finish_reason,
+        })
+        .collect();
+
+    let usage = response.usage.unwrap_or_default();
+
+    CompletionResult {
+        id: response.id,
+        object: "chat.completion".to_string(),
+        created: now().as_secs(),
+        model: response.model,
+        choices,
+        usage: Usage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+        },
+    }
+}
+
+/// Encodes the streaming responses of a completion into the chunks of the OpenAI API.
+pub(crate) struct ChunkEncoder {
+    id: String,
+    created: u64,
+    model: String,
+    /// Choices of which the first chunk with the role has been sent.
+    started: HashSet<u32>,
+}
+
+impl ChunkEncoder {
+    pub(crate) fn new(model: String) -> Self {
+        let now = now();
+        Self {
+            id: format!("chatcmpl-{:x}", now.as_nanos()),
+            created: now.as_secs(),
+            model,
+            started: HashSet::new(),
+        }
+    }
+
+    /// Encodes the delta, where the summary at the end is not encoded.
+    pub(crate) fn encode(
+        &mut self,
+        response: ChatStreamingResponse,
+    ) -> Option<CompletionStreamingChunk> {
+        if response.summary.is_some() {
+            return None;
+        }
+
+        let role = self
+            .started
+            .insert(response.index)
+            .then(|| Role::Assistant.parse_to_string().unwrap());
+
+        Some(CompletionStreamingChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk".to_string(),
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![ChoiceChunk {
+                delta: Delta {
+                    role,
+                    content: (!response.delta.is_empty()).then_some(response.delta),
+                },
+                index: response.index as u64,
+                finish_reason: (!response.finish_reason.is_empty())
+                    .then_some(response.finish_reason),
+            }],
+        })
+    }
+}
+
+/// Error in the format of the OpenAI API.
+pub(crate) fn error_body(status: &Status) -> serde_json::Value {
+    let error_type = match status.code() {
+        Code::InvalidArgument | Code::OutOfRange | Code::FailedPrecondition => {
+            "invalid_request_error"
+        }
+        Code::Unauthenticated => "authentication_error",
+        Code::PermissionDenied => "permission_error",
+        Code::NotFound => "not_found_error",
+        Code::ResourceExhausted => "rate_limit_error",
+        _ => "server_error",
+    };
+
+    serde_json::json!({
+        "error": {
+            "message": status.message(),
+            "type": error_type,
+            "code": format!("{:?}", status.code()),
+        }
+    })
+}
+
+fn now() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Store of the state which survives restart as JSON files in a directory.
+///
+/// Nothing is persisted when the directory is not configured.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Store {
+    directory: Option<PathBuf>,
+}
+
+impl Store {
+    pub(crate) fn new(directory: Option<String>) -> Self {
+        Self {
+            directory: directory.map(PathBuf::from),
+        }
+    }
+
+    /// Loads the state of the name, or the default one if it has not been saved yet.
+    pub(crate) fn load<T: DeserializeOwned + Default>(&self, name: &str) -> Result<T> {
+        let Some(path) = self.path(name) else {
+            return Ok(T::default());
+        };
+        if !path.exists() {
+            return Ok(T::default());
+        }
+
+        let text = std::fs::read_to_string(&path)?;
+        serde_json::from_str::<T>(&text)
+            .map_err(|error| anyhow::anyhow!("Invalid persisted state {:?}: {}", path, error))
+    }
+
+    /// Saves the state of the name, replacing the previous one atomically.
+    pub(crate) async fn save<T: Serialize>(&self, name: &str, state: &T) -> Result<()> {
+        let Some(path) = self.path(name) else {
+            return Ok(());
+        };
+
+        if let Some(directory) = &self.directory {
+            tokio::fs::create_dir_all(directory).await?;
+        }
+
+        let temporary = path.with_extension("json.tmp");
+        tokio::fs::write(&temporary, serde_json::to_string(state)?).await?;
+        tokio::fs::rename(&temporary, &path).await?;
+
+        Ok(())
+    }
+
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        self.directory
+            .as_ref()
+            .map(|directory| directory.join(format!("{}.json", name)))
+    }
+}
@@ -0,0 +1,367 @@
+use crate::api_state::ApiState;
+use crate::persistence::Store;
+use crate::session::session_key;
+use anyhow::Result;
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Status};
+
+/// Name of the persisted consumption of the quotas.
+pub(crate) const QUOTAS_STATE_NAME: &str = "quotas";
+
+/// Limits of a client or a session, no limit if not set.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub(crate) struct Limits {
+    pub(crate) max_tokens_per_day: Option<u64>,
+    /// Maximum cost in USD per calendar month.
+    pub(crate) max_cost_per_month: Option<f64>,
+}
+
+/// Quotas configured like the following, where the limits of a session bound a conversation
+/// of a client and are advisory since the client chooses the session ID,
+/// so the limits of the clients are the ones to enforce the budget:
+///
+/// ```toml
+/// [quotas.per_client]
+/// max_tokens_per_day = 100000
+/// max_cost_per_month = 10.0
+///
+/// [quotas.per_session]
+/// max_tokens_per_day = 20000
+///
+/// [quotas.clients."203.0.113.1"]
+/// max_cost_per_month = 50.0
+/// ```
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub(crate) struct QuotaConfig {
+    pub(crate) per_client: Limits,
+    pub(crate) per_session: Limits,
+    /// Limits of specific clients overriding `per_client`.
+    pub(crate) clients: HashMap<String, Limits>,
+}
+
+/// Consumption of a client or a session in the current day and month in UTC.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct Consumption {
+    day: Option<NaiveDate>,
+    tokens: u64,
+    month: Option<(i32, u32)>,
+    cost: f64,
+}
+
+impl Consumption {
+    /// Starts over at the beginning of a new day or month.
+    fn roll_over(&mut self, now: DateTime<Utc>) {
+        let day = now.date_naive();
+        if self.day != Some(day) {
+            self.day = Some(day);
+            self.tokens = 0;
+        }
+        let month = (day.year(), day.month());
+        if self.month != Some(month) {
+            self.month = Some(month);
+            self.cost = 0.0;
+        }
+    }
+}
+
+/// Enforces the limits on the consumption of each client and session.
+#[derive(Default)]
+pub(crate) struct Quotas {
+    config: QuotaConfig,
+    consumptions: HashMap<String, Consumption>,
+    /// Incremented on every change of the consumptions.
+    version: u64,
+    /// Version of the consumptions saved last.
+    saved_version: u64,
+}
+
+impl Quotas {
+    pub(crate) fn new(config: QuotaConfig, consumptions: HashMap<String, Consumption>) -> Self {
+        Self {
+            config,
+            consumptions,
+            version: 0,
+            saved_version: 0,
+        }
+    }
+
+    /// Checks that neither the client nor the session has exhausted its limits.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn check(
+        &mut self,
+        session_id: &str,
+        client: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), Status> {
+        let client_limits = self.client_limits(client);
+        let session_limits = self.config.per_session;
+
+        for (scope, key, limits) in [
+            ("client", client_key(client), client_limits),
+            (
+                "session",
+                session_quota_key(session_id, client),
+                session_limits,
+            ),
+        ] {
+            // Nothing is recorded until the consumption
+            let mut consumption = self.consumptions.get(&key).cloned().unwrap_or_default();
+            consumption.roll_over(now);
+            check_limits(scope, &consumption, &limits)?;
+        }
+
+        Ok(())
+    }
+
+    /// Consumes the tokens and the cost of a completion by the client in the session.
+    pub(crate) fn consume(
+        &mut self,
+        session_id: &str,
+        client: &str,
+        tokens: u64,
+        cost: f64,
+        now: DateTime<Utc>,
+    ) {
+        for key in [client_key(client), session_quota_key(session_id, client)] {
+            let consumption = self.consumptions.entry(key).or_default();
+            consumption.roll_over(now);
+            consumption.tokens += tokens;
+            consumption.cost += cost;
+        }
+        self.version += 1;
+    }
+
+    /// Removes the consumptions of the past months, which no longer count against any limit.
+    pub(crate) fn prune(&mut self, now: DateTime<Utc>) {
+        let day = now.date_naive();
+        let month = Some((day.year(), day.month()));
+
+        let count = self.consumptions.len();
+        self.consumptions
+            .retain(|_, consumption| consumption.month == month);
+        if self.consumptions.len() != count {
+            self.version += 1;
+        }
+    }
+
+    /// Consumptions to save with the version if they have changed since the last save.
+    pub(crate) fn changes(&self) -> Option<(u64, HashMap<String, Consumption>)> {
+        (self.version != self.saved_version).then(|| (self.version, self.consumptions.clone()))
+    }
+
+    /// Marks the consumptions of the version as saved, so that failed saves are retried.
+    pub(crate) fn mark_saved(&mut self, version: u64) {
+        self.saved_version = self.saved_version.max(version);
+    }
+
+    fn client_limits(&self, client: &str) -> Limits {
+        match self.config.clients.get(client) {
+            Some(limits) => *limits,
+            None => self.config.per_client,
+        }
+    }
+}
+
+/// Saves the consumptions of the quotas if they have changed, pruning the expired ones.
+pub(crate) async fn flush_quotas(state: &Mutex<ApiState>, store: &Store) -> Result<()> {
+    let changes = {
+        let mut state = state.lock().await;
+        state.quotas.prune(Utc::now());
+        state.quotas.changes()
+    };
+    let Some((version, consumptions)) = changes else {
+        return Ok(());
+    };
+
+    // The state is not locked while saving
+    store.save(QUOTAS_STATE_NAME, &consumptions).await?;
+    state.lock().await.quotas.mark_saved(version);
+
+    Ok(())
+}
+
+fn client_key(client: &str) -> String {
+    format!("client:{}", client)
+}
+
+/// Sessions are counted per client, so that a client cannot exhaust the session of another.
+fn session_quota_key(session_id: &str, client: &str) -> String {
+    format!("session:{}", session_key(session_id, client))
+}
+
+/// Fails with `ResourceExhausted` and the remaining budget in the metadata.
+#[allow(clippy::result_large_err)]
+fn check_limits(scope: &str, consumption: &Consumption, limits: &Limits) -> Result<(), Status> {
+    let remaining_tokens = limits
+        .max_tokens_per_day
+        .map(|max| max.saturating_sub(consumption.tokens));
+    let remaining_cost = limits
+        .max_cost_per_month
+        .map(|max| (max - consumption.cost).max(0.0));
+
+    let exhausted =
+        remaining_tokens == Some(0) || remaining_cost.filter(|cost| *cost <= 0.0).is_some();
+    if !exhausted {
+        return Ok(());
+    }
+
+    let mut metadata = MetadataMap::new();
+    metadata.insert("quota-scope", scope.parse().unwrap());
+    if let Some(tokens) = remaining_tokens {
+        metadata.insert(
+            "quota-remaining-tokens-today",
+            tokens.to_string().parse().unwrap(),
+        );
+    }
+    if let Some(cost) = remaining_cost {
+        metadata.insert(
+            "quota-remaining-cost-this-month",
+            format!("{:.4}", cost).parse().unwrap(),
+        );
+    }
+
+    Err(Status::with_metadata(
+        Code::ResourceExhausted,
+        format!("Quota of the {} is exhausted", scope),
+        metadata,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn quotas() -> Quotas {
+        let limits = Limits {
+            max_tokens_per_day: Some(100),
+            max_cost_per_month: Some(1.0),
+        };
+        let config = QuotaConfig {
+            per_client: limits,
+            per_session: Limits {
+                max_tokens_per_day: Some(50),
+                max_cost_per_month: None,
+            },
+            clients: HashMap::new(),
+        };
+        Quotas::new(config, HashMap::new())
+    }
+
+    fn at(month: u32, day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, month, day, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn exhausted_limits_are_enforced() {
+        let mut quotas = quotas();
+
+        quotas.consume("a", "alice", 40, 0.1, at(6, 1));
+        assert!(quotas.check("a", "alice", at(6, 1)).is_ok());
+
+        quotas.consume("a", "alice", 10, 0.1, at(6, 1));
+        let status = quotas.check("a", "alice", at(6, 1)).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(status.metadata().get("quota-scope").unwrap(), "session");
+
+        // Other sessions are limited by the client
+        assert!(quotas.check("b", "alice", at(6, 1)).is_ok());
+        quotas.consume("b", "alice", 50, 0.1, at(6, 1));
+        let status = quotas.check("c", "alice", at(6, 1)).unwrap_err();
+        assert_eq!(status.metadata().get("quota-scope").unwrap(), "client");
+
+        // The session of the same ID of another client is not affected
+        assert!(quotas.check("a", "bob", at(6, 1)).is_ok());
+    }
+
+    #[test]
+    fn windows_roll_over() {
+        let mut quotas = quotas();
+
+        quotas.consume("a", "alice", 100, 0.5, at(6, 1));
+        assert!(quotas.check("b", "alice", at(6, 1)).is_err());
+
+        // The tokens start over on the next day but the cost does not
+        assert!(quotas.check("b", "alice", at(6, 2)).is_ok());
+        quotas.consume("b", "alice", 10, 0.5, at(6, 2));
+        let status = quotas.check("c", "alice", at(6, 2)).unwrap_err();
+        assert_eq!(
+            status
+                .metadata()
+                .get("quota-remaining-cost-this-month")
+                .unwrap(),
+            "0.0000"
+        );
+
+        // The cost starts over on the next month
+        assert!(quotas.check("c", "alice", at(7, 1)).is_ok());
+    }
+
+    #[test]
+    fn checks_record_nothing_and_expired_consumptions_are_pruned() {
+        let mut quotas = quotas();
+
+        quotas.check("a", "alice", at(6, 1)).unwrap();
+        assert!(quotas.consumptions.is_empty());
+        assert!(quotas.changes().is_none());
+
+        quotas.consume("a", "alice", 10, 0.1, at(6, 1));
+        quotas.prune(at(6, 30));
+        assert_eq!(quotas.consumptions.len(), 2);
+
+        quotas.prune(at(7, 1));
+        assert!(quotas.consumptions.is_empty());
+    }
+
+    #[test]
+    fn changes_while_saving_are_saved_again() {
+        let mut quotas = quotas();
+
+        quotas.consume("a", "alice", 10, 0.1, at(6, 1));
+        let (version, _) = quotas.changes().unwrap();
+
+        // Changed while saving
+        quotas.consume("a", "alice", 10, 0.1, at(6, 1));
+        quotas.mark_saved(version);
+        let (version, _) = quotas.changes().unwrap();
+
+        quotas.mark_saved(version);
+        assert!(quotas.changes().is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_saves_are_retried() {
+        let temporary = std::env::temp_dir().join(format!("quotas-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&temporary);
+        std::fs::create_dir_all(&temporary).unwrap();
+        let file = temporary.join("file");
+        std::fs::write(&file, "Not a directory").unwrap();
+        let state = Mutex::new(ApiState {
+            quotas: quotas(),
+            ..ApiState::with_mock()
+        });
+        state
+            .lock()
+            .await
+            .quotas
+            .consume("a", "alice", 10, 0.1, Utc::now());
+
+        // A file cannot be the directory of the store
+        let unwritable = Store::new(Some(file.join("state").to_string_lossy().into_owned()));
+        assert!(flush_quotas(&state, &unwritable).await.is_err());
+        assert!(state.lock().await.quotas.changes().is_some());
+
+        let writable = Store::new(Some(temporary.join("state").to_string_lossy().into_owned()));
+        flush_quotas(&state, &writable).await.unwrap();
+        assert!(state.lock().await.quotas.changes().is_none());
+        let saved: HashMap<String, Consumption> = writable.load(QUOTAS_STATE_NAME).unwrap();
+        assert_eq!(saved.len(), 2);
+
+        std::fs::remove_dir_all(temporary).unwrap();
+    }
+}
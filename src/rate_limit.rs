@@ -0,0 +1,574 @@
+use crate::identity::ClientIdentity;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tonic::body::BoxBody;
+use tonic::codegen::http::{HeaderMap, Request, Response};
+use tonic::codegen::{Body, BoxFuture, Bytes};
+use tonic::metadata::MetadataMap;
+use tonic::server::NamedService;
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tonic::{Code, Status};
+use tower::{Layer, Service};
+
+/// Limits of a client on an RPC method, no limit if not set.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub(crate) struct MethodLimits {
+    /// Rate of the tokens refilled to the bucket, one token per request.
+    pub(crate) requests_per_minute: Option<f64>,
+    /// Capacity of the bucket, a minute of requests by default.
+    pub(crate) burst: Option<f64>,
+    /// Maximum number of calls in progress including open streams.
+    pub(crate) max_concurrent_streams: Option<u32>,
+}
+
+/// Limits of each peer IP address over all methods, checked before the authentication
+/// so that guessing of credentials is throttled too.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub(crate) struct PeerLimits {
+    pub(crate) requests_per_minute: Option<f64>,
+    /// Capacity of the bucket, a minute of requests by default.
+    pub(crate) burst: Option<f64>,
+}
+
+impl Default for PeerLimits {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: Some(600.0),
+            burst: None,
+        }
+    }
+}
+
+/// Rate limits configured like:
+///
+/// ```toml
+/// [rate_limits.per_peer]
+/// requests_per_minute = 600
+///
+/// [rate_limits.default]
+/// requests_per_minute = 60
+/// max_concurrent_streams = 4
+///
+/// [rate_limits.methods."/chat.Chat/CompleteChat"]
+/// requests_per_minute = 10
+/// burst = 3
+/// ```
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub(crate) struct RateLimitConfig {
+    pub(crate) per_peer: PeerLimits,
+    pub(crate) default: MethodLimits,
+    /// Limits per path of the RPC method overriding `default`.
+    pub(crate) methods: HashMap<String, MethodLimits>,
+}
+
+impl RateLimitConfig {
+    fn limits(&self, method: &str) -> MethodLimits {
+        match self.methods.get(method) {
+            Some(limits) => *limits,
+            None => self.default,
+        }
+    }
+}
+
+/// Tower layer to limit requests and concurrent streams of each client per RPC method.
+///
+/// Put it inside the authentication to key the limits by the identity of the client,
+/// and the layer of `per_peer` outside of it.
+#[derive(Clone)]
+pub(crate) struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+impl RateLimitLayer {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        Self {
+            limiter: Arc::new(Limiter {
+                config,
+                state: Mutex::new(LimiterState::default()),
+            }),
+        }
+    }
+
+    /// Takes a token and a slot of the method for a call outside of the gRPC server,
+    /// where the slot is held until the permit is dropped.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn acquire(
+        &self,
+        client: &str,
+        method: &str,
+    ) -> Result<Option<StreamPermit>, Status> {
+        let key = Key {
+            client: client.to_string(),
+            method: method.to_string(),
+        };
+        Limiter::acquire(&self.limiter, key, Instant::now())
+    }
+
+    /// Takes a token of the peer for a call outside of the gRPC server before the authentication.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn acquire_peer(&self, peer: Option<SocketAddr>) -> Result<(), Status> {
+        self.limiter.acquire_peer(peer, Instant::now())
+    }
+
+    /// Layer to limit the requests of each peer, put outside of the authentication.
+    pub(crate) fn per_peer(&self) -> PeerRateLimitLayer {
+        PeerRateLimitLayer {
+            limiter: Arc::clone(&self.limiter),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: Arc::clone(&self.limiter),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl<S, B> Service<Request<B>> for RateLimit<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let key = Key {
+            client: client_key(&request),
+            method: request.uri().path().to_string(),
+        };
+
+        let permit = match Limiter::acquire(&self.limiter, key, Instant::now()) {
+            Err(status) => return Box::pin(async move { Ok(status.to_http()) }),
+            Ok(permit) => permit,
+        };
+
+        // Take the service which has been driven to readiness
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let response = inner.call(request).await?;
+
+            // Hold the permit until the response stream ends
+            Ok(response.map(|body| match permit {
+                None => body,
+                Some(permit) => BoxBody::new(GuardedBody {
+                    inner: body,
+                    _permit: permit,
+                }),
+            }))
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for RateLimit<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[derive(Clone)]
+pub(crate) struct PeerRateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+impl<S> Layer<S> for PeerRateLimitLayer {
+    type Service = PeerRateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PeerRateLimit {
+            inner,
+            limiter: Arc::clone(&self.limiter),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct PeerRateLimit<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl<S, B> Service<Request<B>> for PeerRateLimit<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        if let Err(status) = self
+            .limiter
+            .acquire_peer(remote_addr(&request), Instant::now())
+        {
+            return Box::pin(async move { Ok(status.to_http()) });
+        }
+
+        Box::pin(self.inner.call(request))
+    }
+}
+
+impl<S: NamedService> NamedService for PeerRateLimit<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+/// Identity of the authenticated client, or the remote IP address.
+fn client_key<B>(request: &Request<B>) -> String {
+    if let Some(identity) = request.extensions().get::<ClientIdentity>() {
+        return identity.name.clone();
+    }
+
+    match remote_addr(request) {
+        Some(address) => address.ip().to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+fn remote_addr<B>(request: &Request<B>) -> Option<SocketAddr> {
+    let extensions = request.extensions();
+    match extensions.get::<TlsConnectInfo<TcpConnectInfo>>() {
+        Some(info) => info.get_ref().remote_addr(),
+        None => extensions
+            .get::<TcpConnectInfo>()
+            .and_then(|info| info.remote_addr()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    client: String,
+    method: String,
+}
+
+/// Pseudo method of the limits of a peer over all methods.
+const PEER_METHOD: &str = "*";
+/// Interval to remove the buckets which have been refilled.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    /// Tokens refilled per minute.
+    rate: f64,
+    capacity: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate / 60.0).min(self.capacity);
+        self.updated_at = now;
+    }
+}
+
+#[derive(Default)]
+struct LimiterState {
+    buckets: HashMap<Key, Bucket>,
+    streams: HashMap<Key, u32>,
+    pruned_at: Option<Instant>,
+}
+
+impl LimiterState {
+    /// Takes a token from the bucket of the key.
+    #[allow(clippy::result_large_err)]
+    fn take_token(
+        &mut self,
+        key: &Key,
+        rate: f64,
+        burst: Option<f64>,
+        now: Instant,
+    ) -> Result<(), Status> {
+        self.prune(now);
+
+        let capacity = burst.unwrap_or(rate).max(1.0);
+        let bucket = self.buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: capacity,
+            rate,
+            capacity,
+            updated_at: now,
+        });
+        bucket.refill(now);
+
+        if bucket.tokens < 1.0 {
+            let wait_secs = (1.0 - bucket.tokens) * 60.0 / rate;
+            let mut metadata = MetadataMap::new();
+            metadata.insert(
+                "retry-after-ms",
+                ((wait_secs * 1000.0).ceil() as u64)
+                    .to_string()
+                    .parse()
+                    .unwrap(),
+            );
+            return Err(Status::with_metadata(
+                Code::ResourceExhausted,
+                format!("Too many requests of {}", key.method),
+                metadata,
+            ));
+        }
+        bucket.tokens -= 1.0;
+
+        Ok(())
+    }
+
+    /// Removes the buckets which have been refilled to the capacity,
+    /// since they are the same as new ones.
+    fn prune(&mut self, now: Instant) {
+        if let Some(pruned_at) = self.pruned_at {
+            if now < pruned_at + PRUNE_INTERVAL {
+                return;
+            }
+        }
+        self.pruned_at = Some(now);
+
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.capacity
+        });
+    }
+}
+
+struct Limiter {
+    config: RateLimitConfig,
+    state: Mutex<LimiterState>,
+}
+
+impl Limiter {
+    /// Takes a token from the bucket and a slot of the concurrent streams of the key.
+    #[allow(clippy::result_large_err)]
+    fn acquire(
+        limiter: &Arc<Limiter>,
+        key: Key,
+        now: Instant,
+    ) -> Result<Option<StreamPermit>, Status> {
+        let limits = limiter.config.limits(&key.method);
+        let mut state = limiter.state.lock().unwrap();
+
+        if let Some(streams) = limits.max_concurrent_streams {
+            if state.streams.get(&key).copied().unwrap_or(0) >= streams {
+                return Err(Status::new(
+                    Code::ResourceExhausted,
+                    format!("Too many concurrent streams of {}", key.method),
+                ));
+            }
+        }
+
+        if let Some(rate) = limits.requests_per_minute {
+            state.take_token(&key, rate, limits.burst, now)?;
+        }
+
+        if limits.max_concurrent_streams.is_none() {
+            return Ok(None);
+        }
+        *state.streams.entry(key.clone()).or_default() += 1;
+
+        Ok(Some(StreamPermit {
+            limiter: Arc::clone(limiter),
+            key,
+        }))
+    }
+
+    /// Takes a token from the bucket of the IP address of the peer.
+    #[allow(clippy::result_large_err)]
+    fn acquire_peer(&self, peer: Option<SocketAddr>, now: Instant) -> Result<(), Status> {
+        let limits = self.config.per_peer;
+        let (Some(rate), Some(peer)) = (limits.requests_per_minute, peer) else {
+            return Ok(());
+        };
+
+        let key = Key {
+            client: peer.ip().to_string(),
+            method: PEER_METHOD.to_string(),
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .take_token(&key, rate, limits.burst, now)
+    }
+}
+
+/// Slot of a concurrent stream released on drop.
+pub(crate) struct StreamPermit {
+    limiter: Arc<Limiter>,
+    key: Key,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if let Some(streams) = state.streams.get_mut(&self.key) {
+            *streams -= 1;
+            if *streams == 0 {
+                state.streams.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Response body holding the slot of the stream.
+struct GuardedBody {
+    inner: BoxBody,
+    _permit: StreamPermit,
+}
+
+impl Body for GuardedBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: MethodLimits) -> Arc<Limiter> {
+        Arc::new(Limiter {
+            config: RateLimitConfig {
+                per_peer: PeerLimits {
+                    requests_per_minute: Some(60.0),
+                    burst: Some(2.0),
+                },
+                default: limits,
+                methods: HashMap::new(),
+            },
+            state: Mutex::new(LimiterState::default()),
+        })
+    }
+
+    fn key(client: &str) -> Key {
+        Key {
+            client: client.to_string(),
+            method: "/chat.Chat/CompleteChat".to_string(),
+        }
+    }
+
+    #[test]
+    fn bucket_is_refilled_at_the_rate() {
+        let limiter = limiter(MethodLimits {
+            requests_per_minute: Some(60.0),
+            burst: Some(2.0),
+            max_concurrent_streams: None,
+        });
+        let now = Instant::now();
+
+        assert!(Limiter::acquire(&limiter, key("alice"), now).is_ok());
+        assert!(Limiter::acquire(&limiter, key("alice"), now).is_ok());
+        let status = Limiter::acquire(&limiter, key("alice"), now).err().unwrap();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(status.metadata().get("retry-after-ms").unwrap(), "1000");
+
+        // Other clients have their own buckets
+        assert!(Limiter::acquire(&limiter, key("bob"), now).is_ok());
+
+        // A token per second
+        let later = now + Duration::from_millis(1000);
+        assert!(Limiter::acquire(&limiter, key("alice"), later).is_ok());
+        assert!(Limiter::acquire(&limiter, key("alice"), later).is_err());
+    }
+
+    #[test]
+    fn refilled_buckets_are_pruned() {
+        let limiter = limiter(MethodLimits {
+            requests_per_minute: Some(60.0),
+            burst: Some(2.0),
+            max_concurrent_streams: None,
+        });
+        let now = Instant::now();
+
+        Limiter::acquire(&limiter, key("alice"), now).unwrap();
+        limiter
+            .acquire_peer(Some(([192, 0, 2, 1], 1).into()), now)
+            .unwrap();
+        assert_eq!(limiter.state.lock().unwrap().buckets.len(), 2);
+
+        let later = now + PRUNE_INTERVAL;
+        Limiter::acquire(&limiter, key("bob"), later).unwrap();
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.buckets.len(), 1);
+        assert!(state.buckets.contains_key(&key("bob")));
+    }
+
+    #[test]
+    fn peers_are_limited_over_all_methods() {
+        let limiter = limiter(MethodLimits::default());
+        let now = Instant::now();
+        let peer = Some(([192, 0, 2, 1], 1).into());
+
+        assert!(limiter.acquire_peer(peer, now).is_ok());
+        assert!(limiter.acquire_peer(peer, now).is_ok());
+        assert!(limiter.acquire_peer(peer, now).is_err());
+        assert!(limiter
+            .acquire_peer(Some(([192, 0, 2, 2], 1).into()), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn stream_slot_is_released_with_the_body() {
+        let limiter = limiter(MethodLimits {
+            requests_per_minute: None,
+            burst: None,
+            max_concurrent_streams: Some(1),
+        });
+        let now = Instant::now();
+
+        let permit = Limiter::acquire(&limiter, key("alice"), now)
+            .unwrap()
+            .unwrap();
+        assert!(Limiter::acquire(&limiter, key("alice"), now).is_err());
+
+        let body = GuardedBody {
+            inner: tonic::body::empty_body(),
+            _permit: permit,
+        };
+        assert!(Limiter::acquire(&limiter, key("alice"), now).is_err());
+
+        drop(body);
+        assert!(limiter.state.lock().unwrap().streams.is_empty());
+        assert!(Limiter::acquire(&limiter, key("alice"), now).is_ok());
+    }
+}
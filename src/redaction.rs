@@ -0,0 +1,18 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REDACTS_CONTENT: AtomicBool = AtomicBool::new(true);
+
+/// Sets whether to hide the content of messages in the logs, which is hidden by default.
+#[cfg(feature = "server")]
+pub(crate) fn set_redacts_content(redacts: bool) {
+    REDACTS_CONTENT.store(redacts, Ordering::Relaxed);
+}
+
+/// Content of a message for logs, which is redacted unless configured otherwise.
+pub(crate) fn redact(content: &str) -> String {
+    if REDACTS_CONTENT.load(Ordering::Relaxed) {
+        format!("<redacted {} chars>", content.chars().count())
+    } else {
+        content.to_string()
+    }
+}
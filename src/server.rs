@@ -0,0 +1,295 @@
+use std::sync::Arc;
+
+use crate::accounting::ledger::Ledger;
+use crate::accounting::my_accounting::accounting_rpc::accounting_server::AccountingServer;
+use crate::accounting::my_accounting::MyAccounting;
+use crate::admin::my_admin::admin_rpc::admin_server::AdminServer;
+use crate::admin::my_admin::MyAdmin;
+use crate::api_state::ApiState;
+use crate::auth::Authenticator;
+use crate::certification::build_tls_config;
+use crate::chat::my_chat::chat_rpc::chat_server::ChatServer;
+use crate::chat::my_chat::MyChat;
+use crate::chat_gpt_api::client::Upstream;
+use crate::chat_gpt_api::specification::Model;
+use crate::config::{load_server_config, ServerConfig};
+use crate::gateway::{serve_gateway, Gateway};
+use crate::grpc_web::grpc_web_cors;
+use crate::health::{report_not_serving, report_serving, watch_upstream};
+use crate::logging::init_logging;
+use crate::metrics::exporter::serve_metrics;
+use crate::persistence::Store;
+use crate::quota::{flush_quotas, Quotas, QUOTAS_STATE_NAME};
+use crate::rate_limit::RateLimitLayer;
+use crate::session::{flush_sessions, restore_sessions, SESSIONS_STATE_NAME};
+use crate::shutdown::{shutdown_channel, shutdown_signal};
+use crate::speak::character::load_character_profiles;
+use crate::speak::my_speak::speak_rpc::speak_server::SpeakServer;
+use crate::speak::my_speak::MySpeak;
+use crate::telemetry::{init_tracer, shutdown_tracer, TelemetryLayer};
+use anyhow::Result;
+use std::future::Future;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, Mutex};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::server::NamedService;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::Server;
+use tonic_web::GrpcWebLayer;
+use tower::Layer;
+
+const QUOTAS_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Runs the server configured by `SERVER_CONFIG_PATH` until SIGTERM or Ctrl+C.
+pub async fn run() -> Result<()> {
+    let config = load_server_config()?;
+    init_logging(&config.logging, init_tracer(&config.telemetry)?)?;
+
+    let listener = TcpListener::bind(&config.address).await?;
+    let result = serve(config, listener, shutdown_signal()).await;
+    shutdown_tracer();
+
+    result
+}
+
+/// Serves the services on the listener until the shutdown future completes,
+/// then drains the calls in progress within the grace period.
+pub async fn serve(
+    config: ServerConfig,
+    listener: TcpListener,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let upstream = Arc::new(Upstream::new(config.upstream.clone())?);
+
+    // create our state
+    let model = Model::Gpt35Turbo0613;
+    let prompt = "Your are an AI assistant.".to_string();
+    let memory_size = 10;
+    let characters = load_character_profiles(config.character_profiles_directory.as_deref())?;
+    let store = Store::new(config.persistence_directory.clone());
+    let quotas = Quotas::new(config.quotas.clone(), store.load(QUOTAS_STATE_NAME)?);
+    let sessions = restore_sessions(memory_size, store.load(SESSIONS_STATE_NAME)?);
+    let state = Arc::new(Mutex::new(ApiState {
+        upstream: Arc::clone(&upstream),
+        model,
+        prompt,
+        memory_size,
+        sessions,
+        characters,
+        barge_in: config.barge_in,
+        max_continuations: config.max_continuations,
+        sampling: config.sampling,
+        ledger: Ledger::new(config.prices),
+        quotas,
+    }));
+
+    // Save the consumptions of the quotas periodically until the final flush
+    let (stop_quotas_flush, mut quotas_flush_stopped) = oneshot::channel::<()>();
+    let quotas_flush = {
+        let state = state.clone();
+        let store = store.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(QUOTAS_FLUSH_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {},
+                    _ = &mut quotas_flush_stopped => break,
+                }
+                // A save in progress is finished before stopping
+                if let Err(error) = flush_quotas(&state, &store).await {
+                    tracing::error!(?error, "Failed to save quotas");
+                }
+            }
+        })
+    };
+
+    if config.accounting_log_interval_secs > 0 {
+        let state = state.clone();
+        let period = Duration::from_secs(config.accounting_log_interval_secs);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let state = state.lock().await;
+                let total = &state.ledger.total;
+                tracing::info!(
+                    requests = total.requests,
+                    estimated_requests = total.estimated_requests,
+                    prompt_tokens = total.prompt_tokens,
+                    completion_tokens = total.completion_tokens,
+                    cost = total.cost,
+                    sessions = state.ledger.sessions.len(),
+                    clients = state.ledger.clients.len(),
+                    "Usage"
+                );
+            }
+        });
+    }
+
+    // Servers beside the gRPC one drain on the shutdown too
+    let (shutdown_trigger, shutdown_watch) = shutdown_channel();
+
+    if let Some(metrics_address) = &config.telemetry.metrics_address {
+        let metrics_address = metrics_address.parse()?;
+        let state = state.clone();
+        let shutdown_watch = shutdown_watch.clone();
+        tokio::spawn(async move {
+            if let Err(error) = serve_metrics(metrics_address, state, shutdown_watch).await {
+                tracing::error!(?error, "Failed to serve metrics");
+            }
+        });
+    }
+
+    // Shared with the REST gateway
+    let chat = Arc::new(MyChat {
+        state: state.clone(),
+    });
+
+    let speak = Arc::new(MySpeak {
+        state: state.clone(),
+    });
+
+    let accounting = MyAccounting {
+        state: state.clone(),
+    };
+
+    let admin = MyAdmin {
+        state: state.clone(),
+    };
+
+    let reflection_server = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(crate::chat::my_chat::chat_rpc::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(
+            crate::speak::my_speak::speak_rpc::FILE_DESCRIPTOR_SET,
+        )
+        .register_encoded_file_descriptor_set(
+            crate::accounting::my_accounting::accounting_rpc::FILE_DESCRIPTOR_SET,
+        )
+        .register_encoded_file_descriptor_set(
+            crate::admin::my_admin::admin_rpc::FILE_DESCRIPTOR_SET,
+        )
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()?;
+
+    let authenticator = Arc::new(Authenticator::new(&config.auth)?);
+    if !authenticator.is_enabled() {
+        tracing::warn!("Authentication is disabled since neither keys nor JWKS are configured");
+    }
+
+    // Limit peers before the authentication to throttle guessing of credentials,
+    // and authenticate first to rate limit by the identity of the client
+    let rate_limit = RateLimitLayer::new(config.rate_limits);
+
+    if let Some(gateway_address) = &config.gateway.address {
+        let gateway_address = gateway_address.parse()?;
+        let gateway_config = config.gateway.clone();
+        let gateway = Gateway {
+            chat: Arc::clone(&chat),
+            speak: Arc::clone(&speak),
+            authenticator: Arc::clone(&authenticator),
+            rate_limit: rate_limit.clone(),
+            max_body_bytes: config.gateway.max_body_bytes,
+            shutdown: shutdown_watch.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(error) = serve_gateway(gateway_address, &gateway_config, gateway).await {
+                tracing::error!(?error, "Failed to serve REST gateway");
+            }
+        });
+    }
+
+    drop(shutdown_watch);
+
+    let chat = rate_limit.per_peer().layer(InterceptedService::new(
+        rate_limit.layer(ChatServer::from_arc(chat)),
+        authenticator.interceptor(ChatServer::<MyChat>::NAME),
+    ));
+    let speak = rate_limit.per_peer().layer(InterceptedService::new(
+        rate_limit.layer(SpeakServer::from_arc(speak)),
+        authenticator.interceptor(SpeakServer::<MySpeak>::NAME),
+    ));
+    let accounting = rate_limit.per_peer().layer(InterceptedService::new(
+        rate_limit.layer(AccountingServer::new(accounting)),
+        authenticator.interceptor(AccountingServer::<MyAccounting>::NAME),
+    ));
+    let admin = rate_limit.per_peer().layer(InterceptedService::new(
+        rate_limit.layer(AdminServer::new(admin)),
+        authenticator.interceptor(AdminServer::<MyAdmin>::NAME),
+    ));
+
+    // Probes are neither authenticated nor rate limited
+    let (mut health_reporter, health_server) = tonic_health::server::health_reporter();
+    report_serving(&mut health_reporter).await;
+    let upstream_watch = (config.upstream_check_interval_secs > 0).then(|| {
+        tokio::spawn(watch_upstream(
+            health_reporter.clone(),
+            upstream,
+            Duration::from_secs(config.upstream_check_interval_secs),
+        ))
+    });
+
+    let mut builder = Server::builder();
+    if config.tls {
+        builder = builder.tls_config(build_tls_config()?)?;
+    }
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let server = builder
+        // gRPC-Web of browsers is translated into gRPC before the telemetry
+        .accept_http1(true)
+        .layer(grpc_web_cors(&config.grpc_web)?)
+        .layer(GrpcWebLayer::new())
+        .layer(TelemetryLayer)
+        .add_service(health_server)
+        .add_service(chat)
+        .add_service(speak)
+        .add_service(accounting)
+        .add_service(admin)
+        .add_service(
+            rate_limit
+                .per_peer()
+                .layer(rate_limit.layer(reflection_server)),
+        )
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+            let _ = shutdown_rx.await;
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result?,
+        _ = shutdown => {
+            tracing::info!("Shutting down");
+            if let Some(upstream_watch) = upstream_watch {
+                upstream_watch.abort();
+            }
+            report_not_serving(&mut health_reporter).await;
+
+            // Stop accepting connections and drain the calls in progress
+            let _ = shutdown_tx.send(());
+            shutdown_trigger.stop();
+            let drained = async {
+                let result = (&mut server).await;
+                shutdown_trigger.drained().await;
+                result
+            };
+            let grace_period = Duration::from_secs(config.shutdown_grace_period_secs);
+            match tokio::time::timeout(grace_period, drained).await {
+                Ok(result) => result?,
+                Err(_) => tracing::warn!("Dropped the calls in progress at the deadline"),
+            }
+        }
+    }
+
+    // Not to write the same file as the periodic save
+    let _ = stop_quotas_flush.send(());
+    let _ = quotas_flush.await;
+    if let Err(error) = flush_quotas(&state, &store).await {
+        tracing::error!(?error, "Failed to save quotas");
+    }
+    if let Err(error) = flush_sessions(&state, &store).await {
+        tracing::error!(?error, "Failed to save sessions");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use crate::api_state::ApiState;
+use crate::chat_gpt_api::memory::{FiniteQueueMemory, Memory};
+use crate::chat_gpt_api::specification::Message;
+use crate::identity::{ClientIdentity, Role};
+use crate::persistence::Store;
+use crate::speak::affect::AffectState;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{oneshot, Mutex};
+use tonic::{Code, Status};
+
+/// Name of the persisted context memories of the sessions.
+pub(crate) const SESSIONS_STATE_NAME: &str = "sessions";
+
+pub(crate) struct Session {
+    pub(crate) context_memory: FiniteQueueMemory,
+    pub(crate) character: String,
+    pub(crate) affect: AffectState,
+    /// Held while a completion is in progress in the session.
+    pub(crate) turn_lock: Arc<Mutex<()>>,
+    /// Cancels the completion in progress.
+    pub(crate) in_flight: Option<oneshot::Sender<()>>,
+    /// Indexes and contents of the choices of the last completion
+    /// waiting for one of them to be committed.
+    pub(crate) pending_choices: Vec<(u32, String)>,
+    /// Client bound to the session on the first access.
+    pub(crate) owner: Option<String>,
+}
+
+impl Session {
+    pub(crate) fn new(memory_size: usize, character: String) -> Self {
+        Self {
+            context_memory: FiniteQueueMemory::new(memory_size),
+            character,
+            affect: AffectState::new(),
+            turn_lock: Arc::new(Mutex::new(())),
+            in_flight: None,
+            pending_choices: Vec::new(),
+            owner: None,
+        }
+    }
+
+    /// Restores the session persisted before restart.
+    pub(crate) fn restore(memory_size: usize, persisted: PersistedSession) -> Self {
+        let mut session = Self::new(memory_size, persisted.character);
+        for message in persisted.messages {
+            session.context_memory.add(message);
+        }
+        session.owner = persisted.owner;
+        session
+    }
+
+    fn persisted(&self) -> PersistedSession {
+        PersistedSession {
+            messages: self.context_memory.get(),
+            character: self.character.clone(),
+            owner: self.owner.clone(),
+        }
+    }
+
+    /// Binds the session to the client on the first access,
+    /// and rejects the other clients unless they are operators.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn authorize(&mut self, client: &ClientIdentity) -> Result<(), Status> {
+        match &self.owner {
+            None => {
+                self.owner = Some(client.name.clone());
+                Ok(())
+            }
+            Some(owner) if *owner == client.name || client.role >= Role::Operator => Ok(()),
+            Some(_) => Err(Status::new(
+                Code::PermissionDenied,
+                format!("{} is not the owner of the session", client.name),
+            )),
+        }
+    }
+}
+
+/// ID of the session of a request, where the clients without the ID have their own default sessions
+/// instead of sharing the empty ID of proto3.
+pub(crate) fn session_id_of(session_id: String, client: &ClientIdentity) -> String {
+    if session_id.is_empty() {
+        format!("default:{}", client.name)
+    } else {
+        session_id
+    }
+}
+
+/// Key of the session of the client in the usage like "alice:session",
+/// which is counted per client so that the clients sharing an ID are not mixed up.
+pub(crate) fn session_key(session_id: &str, client: &str) -> String {
+    format!("{}:{}", client, session_id)
+}
+
+/// Session persisted across restarts, without the affect and the turn in progress.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub(crate) struct PersistedSession {
+    messages: Vec<Message>,
+    character: String,
+    owner: Option<String>,
+}
+
+/// Restores the sessions persisted before restart.
+pub(crate) fn restore_sessions(
+    memory_size: usize,
+    persisted: HashMap<String, PersistedSession>,
+) -> HashMap<String, Session> {
+    persisted
+        .into_iter()
+        .map(|(session_id, session)| (session_id, Session::restore(memory_size, session)))
+        .collect()
+}
+
+/// Saves the context memories of the sessions.
+pub(crate) async fn flush_sessions(state: &Mutex<ApiState>, store: &Store) -> Result<()> {
+    let sessions: HashMap<String, PersistedSession> = state
+        .lock()
+        .await
+        .sessions
+        .iter()
+        .map(|(session_id, session)| (session_id.clone(), session.persisted()))
+        .collect();
+
+    // The state is not locked while saving
+    store.save(SESSIONS_STATE_NAME, &sessions).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(name: &str, role: Role) -> ClientIdentity {
+        ClientIdentity {
+            name: name.to_string(),
+            role,
+        }
+    }
+
+    #[test]
+    fn session_is_restored_from_the_persisted_one() {
+        let mut session = Session::new(2, "pikachu".to_string());
+        session.owner = Some("alice".to_string());
+        session.context_memory.add(Message::new(
+            crate::chat_gpt_api::specification::Role::User,
+            "Hi".to_string(),
+        ));
+
+        let json = serde_json::to_string(&session.persisted()).unwrap();
+        let restored = Session::restore(2, serde_json::from_str(&json).unwrap());
+
+        assert_eq!(restored.character, "pikachu");
+        assert_eq!(restored.owner.as_deref(), Some("alice"));
+        let messages = restored.context_memory.get();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content.as_deref(), Some("Hi"));
+    }
+
+    #[test]
+    fn session_is_bound_to_the_first_client() {
+        let mut session = Session::new(10, "default".to_string());
+
+        assert!(session.authorize(&client("alice", Role::User)).is_ok());
+        assert_eq!(session.owner.as_deref(), Some("alice"));
+        assert!(session.authorize(&client("alice", Role::User)).is_ok());
+
+        let status = session.authorize(&client("bob", Role::User)).unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert!(session.authorize(&client("carol", Role::Operator)).is_ok());
+        assert!(session.authorize(&client("dave", Role::Admin)).is_ok());
+        assert_eq!(session.owner.as_deref(), Some("alice"));
+    }
+}
@@ -0,0 +1,101 @@
+use tokio::sync::{mpsc, watch};
+
+/// Waits for SIGTERM or Ctrl+C.
+pub(crate) async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("Failed to listen SIGTERM");
+
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Starts the shutdown of the servers and waits for them to drain.
+pub(crate) struct ShutdownTrigger {
+    stop: watch::Sender<bool>,
+    drain: mpsc::Receiver<()>,
+}
+
+/// Signal of the shutdown held by the servers and their connections until they finish.
+#[derive(Clone)]
+pub(crate) struct ShutdownWatch {
+    stop: watch::Receiver<bool>,
+    _drain: mpsc::Sender<()>,
+}
+
+pub(crate) fn shutdown_channel() -> (ShutdownTrigger, ShutdownWatch) {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let (drain_tx, drain_rx) = mpsc::channel(1);
+
+    (
+        ShutdownTrigger {
+            stop: stop_tx,
+            drain: drain_rx,
+        },
+        ShutdownWatch {
+            stop: stop_rx,
+            _drain: drain_tx,
+        },
+    )
+}
+
+impl ShutdownTrigger {
+    /// Signals the shutdown to the watches.
+    pub(crate) fn stop(&self) {
+        let _ = self.stop.send(true);
+    }
+
+    /// Waits for all watches to be dropped.
+    pub(crate) async fn drained(mut self) {
+        let _ = self.drain.recv().await;
+    }
+}
+
+impl ShutdownWatch {
+    /// Waits for the shutdown, which also completes when the trigger is dropped.
+    pub(crate) async fn stopped(&mut self) {
+        while !*self.stop.borrow() {
+            if self.stop.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn shutdown_drains_until_the_watches_finish() {
+        let (trigger, watch) = shutdown_channel();
+        let (finished_tx, mut finished_rx) = mpsc::unbounded_channel();
+
+        // A connection finishing a little after the shutdown
+        let mut connection = watch.clone();
+        tokio::spawn(async move {
+            connection.stopped().await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            finished_tx.send(()).unwrap();
+        });
+        drop(watch);
+
+        trigger.stop();
+        let drained = trigger.drained();
+        tokio::time::timeout(Duration::from_secs(1), drained)
+            .await
+            .unwrap();
+        assert!(finished_rx.try_recv().is_ok());
+    }
+}
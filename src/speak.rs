@@ -1 +1,4 @@
+pub(super) mod affect;
+pub(super) mod character;
+pub(super) mod conversation;
 pub(super) mod my_speak;
@@ -0,0 +1,182 @@
+use crate::speak::my_speak::speak_rpc::Emotion;
+use std::time::{Duration, Instant};
+
+/// Intensities decay to half in this duration without any stimulus.
+const HALF_LIFE: Duration = Duration::from_secs(60);
+/// Weight of a new stimulus against the current intensity.
+const SMOOTHING: f64 = 0.4;
+/// The character is neutral when no emotion is stronger than this.
+const NEUTRAL_THRESHOLD: f64 = 0.2;
+
+const EMOTIONS: [Emotion; 7] = [
+    Emotion::Neutral,
+    Emotion::Happy,
+    Emotion::Sad,
+    Emotion::Angry,
+    Emotion::Fearful,
+    Emotion::Disgusted,
+    Emotion::Surprised,
+];
+
+/// Affect of a character that carries emotions across turns.
+///
+/// Each reaction stimulates one emotion, and all intensities decay exponentially over time,
+/// so the dominant emotion changes gradually instead of swinging at every message.
+pub(crate) struct AffectState {
+    intensities: [f64; EMOTIONS.len()],
+    updated_at: Instant,
+}
+
+impl AffectState {
+    pub(crate) fn new() -> Self {
+        Self {
+            intensities: [0.0; EMOTIONS.len()],
+            updated_at: Instant::now(),
+        }
+    }
+
+    /// Decays intensities by the time elapsed since the last update.
+    pub(crate) fn decay(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        let factor = 0.5_f64.powf(elapsed.as_secs_f64() / HALF_LIFE.as_secs_f64());
+        for intensity in self.intensities.iter_mut() {
+            *intensity *= factor;
+        }
+        self.updated_at = now;
+    }
+
+    /// Moves the intensity of the emotion towards 1.0 and the others towards 0.0.
+    pub(crate) fn stimulate(&mut self, emotion: Emotion) {
+        for (index, intensity) in self.intensities.iter_mut().enumerate() {
+            let target = if EMOTIONS[index] == emotion { 1.0 } else { 0.0 };
+            *intensity += (target - *intensity) * SMOOTHING;
+        }
+    }
+
+    pub(crate) fn dominant(&self) -> Emotion {
+        self.dominant_among(&EMOTIONS).unwrap_or(Emotion::Neutral)
+    }
+
+    /// Dominant emotion of the allowed ones, or neutral if none of them is strong enough.
+    ///
+    /// None if no allowed emotion is strong enough and neutral is not allowed either.
+    pub(crate) fn dominant_among(&self, allowed: &[Emotion]) -> Option<Emotion> {
+        let (emotion, intensity) = self
+            .intensities()
+            .into_iter()
+            .filter(|(emotion, _)| *emotion != Emotion::Neutral && allowed.contains(emotion))
+            .fold((Emotion::Neutral, 0.0), |max, current| {
+                if current.1 > max.1 {
+                    current
+                } else {
+                    max
+                }
+            });
+
+        if intensity >= NEUTRAL_THRESHOLD {
+            Some(emotion)
+        } else if allowed.contains(&Emotion::Neutral) {
+            Some(Emotion::Neutral)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn intensities(&self) -> Vec<(Emotion, f64)> {
+        EMOTIONS
+            .iter()
+            .cloned()
+            .zip(self.intensities.iter().cloned())
+            .collect()
+    }
+
+    /// Describes the current state for the reaction prompt.
+    pub(crate) fn describe(&self) -> String {
+        let intensities = self
+            .intensities()
+            .into_iter()
+            .map(|(emotion, intensity)| format!("{}: {:.2}", emotion.as_str_name(), intensity))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        format!(
+            "Current emotion intensities of the character from 0.00 to 1.00 are {}. \
+            The dominant emotion is {}. Emotions change gradually.",
+            intensities,
+            self.dominant().as_str_name()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intensity(affect: &AffectState, emotion: Emotion) -> f64 {
+        affect
+            .intensities()
+            .into_iter()
+            .find(|(current, _)| *current == emotion)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn stimulus_is_smoothed() {
+        let mut affect = AffectState::new();
+
+        affect.stimulate(Emotion::Happy);
+        assert!((intensity(&affect, Emotion::Happy) - SMOOTHING).abs() < 1e-9);
+
+        affect.stimulate(Emotion::Sad);
+        assert!((intensity(&affect, Emotion::Happy) - SMOOTHING * (1.0 - SMOOTHING)).abs() < 1e-9);
+        assert!((intensity(&affect, Emotion::Sad) - SMOOTHING).abs() < 1e-9);
+    }
+
+    #[test]
+    fn intensities_decay_to_half_in_half_life() {
+        let mut affect = AffectState::new();
+        affect.stimulate(Emotion::Angry);
+
+        let now = affect.updated_at + HALF_LIFE;
+        affect.decay(now);
+        assert!((intensity(&affect, Emotion::Angry) - SMOOTHING / 2.0).abs() < 1e-9);
+
+        // Time going backwards does not amplify
+        affect.decay(now - HALF_LIFE);
+        assert!((intensity(&affect, Emotion::Angry) - SMOOTHING / 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn weak_emotions_are_neutral() {
+        let mut affect = AffectState::new();
+        assert_eq!(affect.dominant(), Emotion::Neutral);
+
+        affect.stimulate(Emotion::Surprised);
+        assert_eq!(affect.dominant(), Emotion::Surprised);
+
+        // 0.4 decays below the threshold of 0.2 after more than one half-life
+        affect.decay(affect.updated_at + HALF_LIFE * 2);
+        assert_eq!(affect.dominant(), Emotion::Neutral);
+    }
+
+    #[test]
+    fn dominant_is_clamped_to_allowed_emotions() {
+        let mut affect = AffectState::new();
+        for _ in 0..3 {
+            affect.stimulate(Emotion::Sad);
+        }
+        affect.stimulate(Emotion::Happy);
+
+        assert_eq!(affect.dominant(), Emotion::Sad);
+        assert_eq!(
+            affect.dominant_among(&[Emotion::Neutral, Emotion::Happy]),
+            Some(Emotion::Happy)
+        );
+        assert_eq!(
+            affect.dominant_among(&[Emotion::Neutral, Emotion::Angry]),
+            Some(Emotion::Neutral)
+        );
+        assert_eq!(affect.dominant_among(&[Emotion::Angry]), None);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::api_state::ApiState;
+use crate::chat_gpt_api::client::ChoiceDelta;
+use crate::chat_gpt_api::memory::Memory;
+use crate::error_conversion::map_anyhow_error_to_grpc_status;
+use crate::speak::affect::AffectState;
+use crate::speak::my_speak::speak_rpc::converse_request::Action;
+use crate::speak::my_speak::speak_rpc::converse_response::Event;
+use crate::speak::my_speak::speak_rpc::{
+    CommandFailed, ConverseRequest, ConverseResponse, SpeakReaction, TurnFinished, TurnStarted,
+    UtteranceRejected,
+};
+use crate::speak::my_speak::speak_turn;
+use crate::turn::{cancel_turn, is_busy, BargeInPolicy, Completion, Delivery};
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Status, Streaming};
+use tracing::Instrument;
+
+/// Response with the delivery of the turn if it is a delta.
+type ConverseItem = (Result<ConverseResponse, Status>, Option<Delivery>);
+type ConverseSender = mpsc::UnboundedSender<ConverseItem>;
+
+enum Command {
+    /// Utterance queued after the number of resets.
+    Utterance(u64, String),
+    SetPersona(String),
+}
+
+/// Starts a long-lived conversation bound to the session of the first request.
+///
+/// Utterances are spoken in turns one by one in arrival order,
+/// and a new utterance during a turn follows the barge-in policy of the server.
+/// A reset is applied at once, cancelling the turn in progress and dropping the queued utterances.
+/// Failed commands are reported as events, and the conversation ends only with the requests.
+pub(crate) fn start_conversation(
+    state: Arc<Mutex<ApiState>>,
+    client: String,
+    first: ConverseRequest,
+    requests: Streaming<ConverseRequest>,
+) -> impl Stream<Item = Result<ConverseResponse, Status>> + Send + Sync + 'static {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    // The span closes when the conversation finishes
+    tokio::spawn(converse(state, client, first, requests, tx).instrument(tracing::Span::current()));
+
+    UnboundedReceiverStream::new(rx).filter_map(|(response, delivery)| {
+        future::ready(match (response, delivery) {
+            // Deltas after an interruption are not delivered
+            (
+                Ok(ConverseResponse {
+                    event: Some(Event::Delta(delta)),
+                }),
+                Some(delivery),
+            ) => delivery.deliver(0, &delta).then_some(Ok(ConverseResponse {
+                event: Some(Event::Delta(delta)),
+            })),
+            (response, _) => Some(response),
+        })
+    })
+}
+
+async fn converse(
+    state: Arc<Mutex<ApiState>>,
+    client: String,
+    first: ConverseRequest,
+    requests: Streaming<ConverseRequest>,
+    tx: ConverseSender,
+) {
+    let session_id = first.session_id.clone();
+    let policy = state.lock().await.barge_in;
+    let (command_tx, command_rx) = mpsc::unbounded_channel();
+    let resets = Arc::new(AtomicU64::new(0));
+
+    let worker = tokio::spawn(
+        run_commands(
+            Arc::clone(&state),
+            session_id.clone(),
+            client,
+            command_rx,
+            Arc::clone(&resets),
+            tx.clone(),
+        )
+        .in_current_span(),
+    );
+
+    let mut requests = stream::iter(vec![Ok(first)]).chain(requests);
+
+    while let Some(request) = requests.next().await {
+        let request = match request {
+            Err(status) => {
+                let _ = tx.send((Err(status), None));
+                break;
+            }
+            Ok(request) => request,
+        };
+
+        // Requests after the first one may omit the session
+        if !request.session_id.is_empty() && request.session_id != session_id {
+            let status = Status::new(
+                tonic::Code::InvalidArgument,
+                format!(
+                    "The conversation is bound to another session: {}",
+                    request.session_id
+                ),
+            );
+            let _ = report_failure(&tx, 0, status);
+            continue;
+        }
+
+        let queued = |utterance| Command::Utterance(resets.load(Ordering::SeqCst), utterance);
+        let command = match request.action {
+            None => continue,
+            Some(Action::Utterance(utterance)) => match policy {
+                BargeInPolicy::Queue => queued(utterance),
+                BargeInPolicy::Reject if is_busy(&state, &session_id).await => {
+                    let _ = send(
+                        &tx,
+                        Event::UtteranceRejected(UtteranceRejected { utterance }),
+                    );
+                    continue;
+                }
+                BargeInPolicy::Reject => queued(utterance),
+                BargeInPolicy::CancelAndReplace => {
+                    cancel_turn(&state, &session_id).await;
+                    queued(utterance)
+                }
+            },
+            Some(Action::Interrupt(_)) => {
+                cancel_turn(&state, &session_id).await;
+                continue;
+            }
+            // Out of band, not to wait for the queued utterances
+            Some(Action::Reset(_)) => {
+                resets.fetch_add(1, Ordering::SeqCst);
+                cancel_turn(&state, &session_id).await;
+                if let Err(status) = reset(&state, &session_id).await {
+                    let _ = report_failure(&tx, 0, status);
+                }
+                continue;
+            }
+            Some(Action::SetPersona(character)) => Command::SetPersona(character),
+        };
+
+        // The worker stops when the client has gone
+        if command_tx.send(command).is_err() {
+            break;
+        }
+    }
+
+    // Let the worker finish the queued commands
+    drop(command_tx);
+    let _ = worker.await;
+}
+
+async fn run_commands(
+    state: Arc<Mutex<ApiState>>,
+    session_id: String,
+    client: String,
+    mut command_rx: mpsc::UnboundedReceiver<Command>,
+    resets: Arc<AtomicU64>,
+    tx: ConverseSender,
+) {
+    let mut turn_id = 0;
+
+    while let Some(command) = command_rx.recv().await {
+        let (command_turn_id, result) = match command {
+            // Dropped by a reset after it was queued
+            Command::Utterance(queued_resets, _)
+                if queued_resets != resets.load(Ordering::SeqCst) =>
+            {
+                continue
+            }
+            Command::Utterance(_, message) => {
+                turn_id += 1;
+                let result = run_turn(&state, &session_id, &client, turn_id, message, &tx).await;
+                (turn_id, result)
+            }
+            Command::SetPersona(character) => {
+                (0, set_persona(&state, &session_id, character).await)
+            }
+        };
+
+        if let Err(status) = result {
+            // The client has gone
+            if tx.is_closed() {
+                break;
+            }
+
+            // A failed command does not end the conversation
+            let _ = report_failure(&tx, command_turn_id, status);
+        }
+    }
+}
+
+async fn run_turn(
+    state: &Arc<Mutex<ApiState>>,
+    session_id: &str,
+    client: &str,
+    turn_id: u64,
+    message: String,
+    tx: &ConverseSender,
+) -> Result<(), Status> {
+    send(tx, Event::TurnStarted(TurnStarted { turn_id }))?;
+
+    let (reaction_tx, reaction_rx) = oneshot::channel();
+    let (delta_tx, delta_rx) = mpsc::unbounded_channel();
+    let delivery = Delivery::default();
+
+    // The barge-in policy has been applied on arrival of the utterance
+    let turn = tokio::spawn(
+        speak_turn(
+            Arc::clone(state),
+            session_id.to_string(),
+            client.to_string(),
+            message,
+            BargeInPolicy::Queue,
+            reaction_tx,
+            delta_tx,
+            delivery.clone(),
+        )
+        .in_current_span(),
+    );
+
+    let result = relay(tx, reaction_rx, delta_rx, &delivery).await;
+    let interrupted = matches!(turn.await, Ok(Completion::Interrupted(_)));
+    result?;
+
+    send(
+        tx,
+        Event::TurnFinished(TurnFinished {
+            turn_id,
+            interrupted,
+        }),
+    )
+}
+
+/// Relays the reaction and the deltas of a turn to the client.
+async fn relay(
+    tx: &ConverseSender,
+    reaction_rx: oneshot::Receiver<Result<SpeakReaction, Status>>,
+    mut delta_rx: mpsc::UnboundedReceiver<anyhow::Result<ChoiceDelta>>,
+    delivery: &Delivery,
+) -> Result<(), Status> {
+    // The reaction is dropped when the turn is interrupted before it is decided
+    if let Ok(reaction) = reaction_rx.await {
+        send(tx, Event::Reaction(reaction?))?;
+    }
+
+    while let Some(delta) = delta_rx.recv().await {
+        let delta = delta.map_err(map_anyhow_error_to_grpc_status)?.content;
+        // Skip the end of the utterance
+        if delta.is_empty() {
+            continue;
+        }
+        tx.send((
+            Ok(ConverseResponse {
+                event: Some(Event::Delta(delta)),
+            }),
+            Some(delivery.clone()),
+        ))
+        .map_err(|_| closed())?;
+    }
+
+    Ok(())
+}
+
+/// Clears the session once the cancelled turn has released it,
+/// so that the turn does not record its reply after the reset.
+async fn reset(state: &Arc<Mutex<ApiState>>, session_id: &str) -> Result<(), Status> {
+    let turn_lock = Arc::clone(&state.lock().await.session(session_id).turn_lock);
+    let _turn = turn_lock.lock().await;
+    let mut state = state.lock().await;
+
+    let session = state.session(session_id);
+    session.context_memory.clear();
+    session.affect = AffectState::new();
+
+    Ok(())
+}
+
+async fn set_persona(
+    state: &Arc<Mutex<ApiState>>,
+    session_id: &str,
+    character: String,
+) -> Result<(), Status> {
+    let mut state = state.lock().await;
+
+    if !state.characters.contains_key(&character) {
+        return Err(Status::new(
+            tonic::Code::NotFound,
+            format!("Character not found: {}", character),
+        ));
+    }
+
+    state.session(session_id).character = character;
+
+    Ok(())
+}
+
+#[allow(clippy::result_large_err)]
+fn send(tx: &ConverseSender, event: Event) -> Result<(), Status> {
+    tx.send((Ok(ConverseResponse { event: Some(event) }), None))
+        .map_err(|_| closed())
+}
+
+#[allow(clippy::result_large_err)]
+fn report_failure(tx: &ConverseSender, turn_id: u64, status: Status) -> Result<(), Status> {
+    send(
+        tx,
+        Event::Failed(CommandFailed {
+            turn_id,
+            code: status.code() as i32,
+            message: status.message().to_string(),
+        }),
+    )
+}
+
+fn closed() -> Status {
+    Status::new(
+        tonic::Code::Cancelled,
+        "Conversation was closed".to_string(),
+    )
+}
@@ -1,28 +1,46 @@
-pub(crate) mod speak_rpc {
+/// Messages, client and server generated from speak.proto.
+pub mod speak_rpc {
+    // Documented by the comments of the proto
+    #![allow(missing_docs)]
+    #![allow(clippy::enum_variant_names)]
     tonic::include_proto!("speak");
 
     pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
         tonic::include_file_descriptor_set!("speak_descriptor");
 }
 
-use crate::api_state::ApiState;
-use crate::chat_gpt_api::client::complete_chat;
+use crate::api_state::{ApiState, Prompt};
+use crate::chat_gpt_api::client::ChoiceDelta;
 use crate::chat_gpt_api::memory::Memory;
 use crate::chat_gpt_api::specification::{
-    Function, FunctionCallingSpecification, Message, Options, Role,
+    CompletionResult, FunctionCall, FunctionCallingSpecification, Message, Role,
 };
 use crate::error_conversion::map_anyhow_error_to_grpc_status;
+use crate::identity::{client_identity, client_of};
+use crate::redaction::redact;
+use crate::session::session_id_of;
+use crate::speak::character::CharacterProfile;
+use crate::speak::conversation::start_conversation;
+use crate::turn::{begin_turn, interrupted, BargeInPolicy, Completion, Delivery};
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
 use speak_rpc::speak_server::Speak;
+use speak_rpc::speak_streaming_response::Content;
 use speak_rpc::{Cry, Emotion, Motion};
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tonic::{Request, Response, Status};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
+use tonic::{Request, Response, Status, Streaming};
+use tracing::field::Empty;
+use tracing::Instrument;
 
 pub struct MySpeak {
     pub(crate) state: Arc<Mutex<ApiState>>,
 }
 
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct SpeakReactionJson {
     emotion: String,
     motion: String,
@@ -32,162 +50,491 @@ struct SpeakReactionJson {
 #[tonic::async_trait]
 impl Speak for MySpeak {
     // grpcurl -plaintext -d '{ "message": "おはよう!" }' localhost:8000 speak.Speak/SpeakTo
+    #[tracing::instrument(
+        name = "speak.Speak/SpeakTo",
+        skip_all,
+        fields(session_id = %request.get_ref().session_id, client = %client_identity(&request))
+    )]
+    #[allow(clippy::result_large_err)]
     async fn speak_to(
         &self,
         request: Request<speak_rpc::SpeakContent>,
     ) -> Result<Response<speak_rpc::SpeakReaction>, Status> {
-        let mut state = self.state.lock().await;
-
-        let address = request.remote_addr();
-        println!(
-            "Got a request to speak to: {:?} from {:?}",
-            request, address
+        tracing::info!(
+            message = %redact(&request.get_ref().message),
+            "Got a request to speak to"
         );
 
-        state.context_memory.add(Message {
-            role: Role::User.parse_to_string().unwrap(),
-            content: Some(request.into_inner().message),
-            name: None,
-            function_call: None,
-        });
+        let identity = client_of(&request);
+        let client = identity.name.clone();
+        let request = request.into_inner();
+        let session_id = session_id_of(request.session_id, &identity);
 
-        let context = state.context_memory.get();
-        let messages = build_messages(state.prompt.clone(), context.clone());
-        let functions = vec![Function::new(
-            "reaction_generator".to_string(),
-            Some("Generate reaction of AI character like Pokemon from conversations.".to_string()),
-            r#"{
-                "type": "object",
-                "properties": {
-                    "emotion": {
-                        "type": "string",
-                        "enum": [
-                            "EMOTION_NEUTRAL",
-                            "EMOTION_HAPPY",
-                            "EMOTION_SAD",
-                            "EMOTION_ANGRY",
-                            "EMOTION_FEARFUL",
-                            "EMOTION_DISGUSTED",
-                            "EMOTION_SURPRISED"
-                        ]
-                    },
-                    "motion": {
-                        "type": "string",
-                        "enum": [
-                            "MOTION_NEUTRAL",
-                            "MOTION_HAPPY",
-                            "MOTION_SAD",
-                            "MOTION_ANGRY",
-                            "MOTION_FEARFUL",
-                            "MOTION_DISGUSTED",
-                            "MOTION_SURPRISED",
-                            "MOTION_DANCE",
-                            "MOTION_FLOAT",
-                            "MOTION_SLEEP"
-                        ]
-                    },
-                    "cry": {
-                        "type": "string",
-                        "enum": [
-                            "CRY_NONE",
-                            "CRY_HAPPY",
-                            "CRY_SAD",
-                            "CRY_ANGRY",
-                            "CRY_FEARFUL",
-                            "CRY_DISGUSTED",
-                            "CRY_SURPRISED",
-                            "CRY_SPOILED",
-                            "CRY_CRY"
-                        ]
-                    }
-                },
-                "required": [
-                    "emotion",
-                    "motion",
-                    "cry"
-                ]
-            }"#
-            .to_string(),
-        )];
-
-        let options: Options = Options {
-            model: state.model.parse_to_string().unwrap(),
-            messages,
-            functions: Some(functions),
-            function_call: Some(FunctionCallingSpecification::Name(
-                "reaction_generator".to_string(),
-            )),
-            temperature: None,
-            top_p: None,
-            n: None,
-            stream: None,
-            stop: None,
-            max_tokens: None,
-            presence_penalty: None,
-            frequency_penalty: None,
-            logit_bias: None,
-            user: None,
+        let policy = {
+            let mut state = self.state.lock().await;
+            state.authorize_session(&session_id, &identity)?;
+            state.check_quota(&session_id, &client)?;
+            state.barge_in
         };
+        let mut turn = begin_turn(&self.state, &session_id, policy).await?;
+
+        self.state
+            .lock()
+            .await
+            .session(&session_id)
+            .context_memory
+            .add(Message::new(Role::User, request.message));
+
+        let reaction = turn
+            .complete(generate_reaction(&self.state, &session_id, &client))
+            .await;
+        turn.finish(&mut *self.state.lock().await, None);
+        let speak_reaction = reaction.unwrap_or_else(|| Err(interrupted()))?;
+
+        tracing::info!(reaction = ?speak_reaction, "Responding to speak to");
+
+        Ok(Response::new(speak_reaction))
+    }
+
+    type SpeakToStreamingStream = Pin<
+        Box<
+            dyn Stream<Item = Result<speak_rpc::SpeakStreamingResponse, Status>>
+                + Send
+                + Sync
+                + 'static,
+        >,
+    >;
+
+    // grpcurl -plaintext -d '{ "message": "おはよう!" }' localhost:8000 speak.Speak/SpeakToStreaming
+    #[tracing::instrument(
+        name = "speak.Speak/SpeakToStreaming",
+        skip_all,
+        fields(session_id = %request.get_ref().session_id, client = %client_identity(&request))
+    )]
+    async fn speak_to_streaming(
+        &self,
+        request: Request<speak_rpc::SpeakContent>,
+    ) -> Result<Response<Self::SpeakToStreamingStream>, Status> {
+        let (reaction_tx, reaction_rx) = oneshot::channel();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let state = Arc::clone(&self.state);
+
+        tracing::info!(
+            message = %redact(&request.get_ref().message),
+            "Got a request to speak to streaming"
+        );
+
+        let delivery = Delivery::default();
+        let turn_delivery = delivery.clone();
 
-        match complete_chat(options, true).await {
-            Err(error) => {
-                let error = anyhow::anyhow!("Error in speak to: {:?}", error);
-                Err(map_anyhow_error_to_grpc_status(error))
+        let identity = client_of(&request);
+        let mut request = request.into_inner();
+        request.session_id = session_id_of(request.session_id, &identity);
+        self.state
+            .lock()
+            .await
+            .authorize_session(&request.session_id, &identity)?;
+
+        let client = identity.name;
+        tokio::spawn(
+            async move {
+                let policy = state.lock().await.barge_in;
+                let completion = speak_turn(
+                    state,
+                    request.session_id,
+                    client,
+                    request.message,
+                    policy,
+                    reaction_tx,
+                    tx.clone(),
+                    turn_delivery,
+                )
+                .await;
+
+                if let Completion::Interrupted(_) = completion {
+                    let _ = tx.send(Err(anyhow::Error::new(interrupted())));
+                }
             }
-            Ok(response) => match response.choices.get(0) {
-                None => Err(Status::new(
+            // The span closes when the stream finishes
+            .instrument(tracing::Span::current()),
+        );
+
+        tracing::info!("Responding to speak to streaming");
+
+        let reaction_stream = stream::once(async move {
+            match reaction_rx.await {
+                Ok(Ok(reaction)) => Ok(speak_rpc::SpeakStreamingResponse {
+                    content: Some(Content::Reaction(reaction)),
+                }),
+                Ok(Err(status)) => Err(status),
+                Err(_) => Err(Status::new(
                     tonic::Code::Internal,
-                    "No choices in response".to_string(),
+                    "Reaction was not generated".to_string(),
                 )),
-                Some(choice) => match &choice.message.function_call {
-                    None => Err(Status::new(
-                        tonic::Code::Internal,
-                        "No function calling in response".to_string(),
-                    )),
-                    // Success
-                    Some(function_call) => {
-                        state.context_memory.add(Message {
-                            role: Role::Function.parse_to_string().unwrap(),
-                            content: None,
-                            name: None,
-                            function_call: Some(function_call.clone()),
-                        });
-
-                        let speak_reaction =
-                            serde_json::from_str::<SpeakReactionJson>(&function_call.arguments)
-                                .unwrap();
-
-                        println!(
-                            "Responding to speak to with: {:?} to {:?}",
-                            response, address
-                        );
-
-                        Ok(Response::new(speak_rpc::SpeakReaction {
-                            emotion: Emotion::from_str_name(&speak_reaction.emotion).unwrap()
-                                as i32,
-                            motion: Motion::from_str_name(&speak_reaction.motion).unwrap() as i32,
-                            cry: Cry::from_str_name(&speak_reaction.cry).unwrap() as i32,
-                        }))
-                    }
-                },
-            },
+            }
+        });
+
+        // Wrap the receiver in a UnboundedReceiverStream
+        let rx = UnboundedReceiverStream::new(rx);
+
+        let delta_stream = rx.filter_map(move |result| {
+            future::ready(match result {
+                Err(error) => Some(Err(map_anyhow_error_to_grpc_status(error))),
+                // Skip the end of the utterance
+                Ok(delta) if delta.content.is_empty() => None,
+                // Deltas after an interruption are not delivered
+                Ok(delta) if !delivery.deliver(delta.index, &delta.content) => {
+                    Some(Err(interrupted()))
+                }
+                Ok(delta) => Some(Ok(speak_rpc::SpeakStreamingResponse {
+                    content: Some(Content::Delta(delta.content)),
+                })),
+            })
+        });
+
+        Ok(Response::new(
+            Box::pin(reaction_stream.chain(delta_stream)) as Self::SpeakToStreamingStream
+        ))
+    }
+
+    type ConverseStream = Pin<
+        Box<dyn Stream<Item = Result<speak_rpc::ConverseResponse, Status>> + Send + Sync + 'static>,
+    >;
+
+    // grpcurl -plaintext -d @ localhost:8000 speak.Speak/Converse
+    #[tracing::instrument(
+        name = "speak.Speak/Converse",
+        skip_all,
+        fields(session_id = Empty, client = %client_identity(&request))
+    )]
+    async fn converse(
+        &self,
+        request: Request<Streaming<speak_rpc::ConverseRequest>>,
+    ) -> Result<Response<Self::ConverseStream>, Status> {
+        let state = Arc::clone(&self.state);
+
+        tracing::info!("Got a request to converse");
+
+        let identity = client_of(&request);
+        let mut requests = request.into_inner();
+
+        // Bind the conversation to the session of the first request
+        let first = match requests.message().await? {
+            None => {
+                return Err(Status::new(
+                    tonic::Code::InvalidArgument,
+                    "No request to converse".to_string(),
+                ))
+            }
+            Some(mut first) => {
+                first.session_id = session_id_of(first.session_id, &identity);
+                first
+            }
+        };
+        tracing::Span::current().record("session_id", first.session_id.as_str());
+        state
+            .lock()
+            .await
+            .authorize_session(&first.session_id, &identity)?;
+
+        let output_stream = start_conversation(state, identity.name, first, requests);
+
+        tracing::info!("Responding to converse");
+
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::ConverseStream
+        ))
+    }
+
+    // grpcurl -plaintext -d '{}' localhost:8000 speak.Speak/ListCharacters
+    async fn list_characters(
+        &self,
+        _request: Request<speak_rpc::ListCharactersRequest>,
+    ) -> Result<Response<speak_rpc::CharacterList>, Status> {
+        let state = self.state.lock().await;
+
+        let mut names: Vec<String> = state.characters.keys().cloned().collect();
+        names.sort();
+
+        Ok(Response::new(speak_rpc::CharacterList { names }))
+    }
+
+    // grpcurl -plaintext -d '{ "session_id": "alice", "character": "pikachu" }' localhost:8000 speak.Speak/SelectCharacter
+    #[tracing::instrument(
+        name = "speak.Speak/SelectCharacter",
+        skip_all,
+        fields(session_id = %request.get_ref().session_id, client = %client_identity(&request))
+    )]
+    async fn select_character(
+        &self,
+        request: Request<speak_rpc::CharacterSelection>,
+    ) -> Result<Response<speak_rpc::CharacterSelection>, Status> {
+        let mut state = self.state.lock().await;
+
+        tracing::info!(
+            character = %request.get_ref().character,
+            "Got a request to select character"
+        );
+
+        let identity = client_of(&request);
+        let mut selection = request.into_inner();
+        selection.session_id = session_id_of(selection.session_id, &identity);
+        if !state.characters.contains_key(&selection.character) {
+            return Err(Status::new(
+                tonic::Code::NotFound,
+                format!("Character not found: {}", selection.character),
+            ));
+        }
+
+        state
+            .authorize_session(&selection.session_id, &identity)?
+            .character = selection.character.clone();
+
+        Ok(Response::new(selection))
+    }
+
+    // grpcurl -plaintext -d '{ "session_id": "alice" }' localhost:8000 speak.Speak/GetEmotionState
+    async fn get_emotion_state(
+        &self,
+        request: Request<speak_rpc::EmotionStateRequest>,
+    ) -> Result<Response<speak_rpc::EmotionState>, Status> {
+        let mut state = self.state.lock().await;
+
+        let identity = client_of(&request);
+        let session_id = session_id_of(request.into_inner().session_id, &identity);
+        // Reading the state does not create a session
+        let affect = match state.sessions.get_mut(&session_id) {
+            None => {
+                return Err(Status::new(
+                    tonic::Code::NotFound,
+                    format!("Session not found: {}", session_id),
+                ))
+            }
+            Some(session) => {
+                session.authorize(&identity)?;
+                &mut session.affect
+            }
+        };
+        affect.decay(Instant::now());
+
+        Ok(Response::new(speak_rpc::EmotionState {
+            dominant: affect.dominant() as i32,
+            intensities: affect
+                .intensities()
+                .into_iter()
+                .map(|(emotion, intensity)| speak_rpc::EmotionIntensity {
+                    emotion: emotion as i32,
+                    intensity,
+                })
+                .collect(),
+        }))
+    }
+}
+
+/// Runs a turn of the character in the session:
+/// records the message, decides the reaction and then streams the utterance.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn speak_turn(
+    state: Arc<Mutex<ApiState>>,
+    session_id: String,
+    client: String,
+    message: String,
+    policy: BargeInPolicy,
+    reaction_tx: oneshot::Sender<Result<speak_rpc::SpeakReaction, Status>>,
+    tx: mpsc::UnboundedSender<anyhow::Result<ChoiceDelta>>,
+    delivery: Delivery,
+) -> Completion {
+    if let Err(status) = state.lock().await.check_quota(&session_id, &client) {
+        let _ = reaction_tx.send(Err(status));
+        return Completion::Failed(Vec::new());
+    }
+
+    let mut turn = match begin_turn(&state, &session_id, policy).await {
+        Err(status) => {
+            let _ = reaction_tx.send(Err(status));
+            return Completion::Failed(Vec::new());
+        }
+        Ok(turn) => turn,
+    };
+
+    state
+        .lock()
+        .await
+        .session(&session_id)
+        .context_memory
+        .add(Message::new(Role::User, message));
+
+    // Decide the reaction first so that the client can start the animation
+    // before the utterance is generated.
+    let reaction = tokio::select! {
+        reaction = generate_reaction(&state, &session_id, &client) => reaction,
+        _ = turn.cancelled() => {
+            turn.finish(&mut *state.lock().await, None);
+            return Completion::Interrupted(Vec::new());
+        }
+    };
+    let failed = reaction.is_err();
+    if reaction_tx.send(reaction).is_err() || failed {
+        turn.finish(&mut *state.lock().await, None);
+        return Completion::Failed(Vec::new());
+    }
+
+    let options = state
+        .lock()
+        .await
+        .options(&session_id, Prompt::Character)
+        .stream(true);
+
+    let model = options.model.clone();
+    let messages = options.messages.clone();
+    let completion = turn.stream(tx, options, &delivery).await;
+
+    let mut state = state.lock().await;
+    state.record_completion(&session_id, &client, &model, &messages, &completion);
+    turn.finish(&mut state, completion.reply(1));
+
+    completion
+}
+
+/// Generates the reaction of the character to the context of the session by function calling
+/// and records the function call in the context memory.
+///
+/// The emotion of the reaction is the dominant one of the affect state after the stimulus,
+/// so that it does not swing between consecutive messages,
+/// limited to the emotions of the character.
+async fn generate_reaction(
+    state: &Mutex<ApiState>,
+    session_id: &str,
+    client: &str,
+) -> Result<speak_rpc::SpeakReaction, Status> {
+    let (profile, options, upstream) = {
+        let mut state = state.lock().await;
+
+        let profile = state.character(session_id);
+        let session = state.session(session_id);
+        session.affect.decay(Instant::now());
+        let affect = session.affect.describe();
+
+        let function = profile
+            .reaction_function()
+            .map_err(map_anyhow_error_to_grpc_status)?;
+        let mut options = state
+            .options(session_id, Prompt::Character)
+            .functions(vec![function])
+            .function_call(FunctionCallingSpecification::Name(
+                "reaction_generator".to_string(),
+            ));
+        // Affect of the character after the context
+        options.messages.push(Message::new(Role::System, affect));
+
+        (profile, options, Arc::clone(&state.upstream))
+    };
+
+    let model = options.model.clone();
+    match upstream.complete_chat(options).await {
+        Err(error) => {
+            let error = anyhow::anyhow!("Error in speak to: {:?}", error);
+            Err(map_anyhow_error_to_grpc_status(error))
+        }
+        Ok(response) => {
+            state
+                .lock()
+                .await
+                .record_usage(session_id, client, &model, &response.usage, false);
+            react(state, session_id, &profile, &response).await
         }
     }
 }
 
-fn build_messages(prompt: String, context: Vec<Message>) -> Vec<Message> {
-    let mut messages = Vec::new();
+/// Parses the reaction in the function call of the response and records it.
+async fn react(
+    state: &Mutex<ApiState>,
+    session_id: &str,
+    profile: &CharacterProfile,
+    response: &CompletionResult,
+) -> Result<speak_rpc::SpeakReaction, Status> {
+    match response.choices.first() {
+        None => Err(Status::new(
+            tonic::Code::Internal,
+            "No choices in response".to_string(),
+        )),
+        Some(choice) => match &choice.message.function_call {
+            None => Err(Status::new(
+                tonic::Code::Internal,
+                "No function calling in response".to_string(),
+            )),
+            // Success
+            Some(function_call) => {
+                let mut speak_reaction = parse_reaction(profile, &function_call.arguments)?;
+
+                let mut state = state.lock().await;
+                let session = state.session(session_id);
 
-    messages.push(Message {
-        role: Role::System.parse_to_string().unwrap(),
-        content: Some(prompt),
-        name: None,
-        function_call: None,
-    });
+                session.affect.stimulate(speak_reaction.emotion());
+                if let Some(emotion) = session
+                    .affect
+                    .dominant_among(&profile.allowed_emotion_values())
+                {
+                    speak_reaction.emotion = emotion as i32;
+                }
 
-    for message in context {
-        messages.push(message);
+                // Record as the assistant's function call to keep the context valid for the API,
+                // with the final reaction so that the context agrees with the client
+                session
+                    .context_memory
+                    .add(Message::function_call(FunctionCall {
+                        name: function_call.name.clone(),
+                        arguments: reaction_arguments(&speak_reaction),
+                    }));
+
+                Ok(speak_reaction)
+            }
+        },
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_reaction(
+    profile: &CharacterProfile,
+    arguments: &str,
+) -> Result<speak_rpc::SpeakReaction, Status> {
+    let invalid = |what: &str| {
+        Status::new(
+            tonic::Code::Internal,
+            format!("Invalid {} in reaction: {}", what, arguments),
+        )
+    };
+
+    let speak_reaction =
+        serde_json::from_str::<SpeakReactionJson>(arguments).map_err(|_| invalid("JSON"))?;
+
+    // The model may ignore the subsets of the character
+    if !profile.allowed_emotions().contains(&speak_reaction.emotion) {
+        return Err(invalid("emotion"));
+    }
+    if !profile.allowed_motions().contains(&speak_reaction.motion) {
+        return Err(invalid("motion"));
+    }
+    if !profile.allowed_cries().contains(&speak_reaction.cry) {
+        return Err(invalid("cry"));
     }
 
-    messages
+    Ok(speak_rpc::SpeakReaction {
+        emotion: Emotion::from_str_name(&speak_reaction.emotion)
+            .ok_or_else(|| invalid("emotion"))? as i32,
+        motion: Motion::from_str_name(&speak_reaction.motion).ok_or_else(|| invalid("motion"))?
+            as i32,
+        cry: Cry::from_str_name(&speak_reaction.cry).ok_or_else(|| invalid("cry"))? as i32,
+    })
+}
+
+/// Arguments of the function call of the reaction in JSON.
+fn reaction_arguments(reaction: &speak_rpc::SpeakReaction) -> String {
+    let json = SpeakReactionJson {
+        emotion: reaction.emotion().as_str_name().to_string(),
+        motion: reaction.motion().as_str_name().to_string(),
+        cry: reaction.cry().as_str_name().to_string(),
+    };
+
+    serde_json::to_string(&json).unwrap()
 }
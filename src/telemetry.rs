@@ -0,0 +1,226 @@
+use crate::metrics::METRICS;
+use anyhow::Result;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use serde::Deserialize;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{HeaderMap, Request, Response};
+use tonic::codegen::{Body, BoxFuture, Bytes};
+use tonic::{Code, Status};
+use tower::{Layer, Service};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Telemetry configured like:
+///
+/// ```toml
+/// [telemetry]
+/// metrics_address = "0.0.0.0:9100"
+/// otlp_endpoint = "http://localhost:4317"
+/// service_name = "llm-agent"
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct TelemetryConfig {
+    /// Address of the HTTP server of the Prometheus metrics, disabled if not set.
+    pub(crate) metrics_address: Option<String>,
+    /// Endpoint of the OTLP collector over gRPC to export the traces, disabled if not set.
+    pub(crate) otlp_endpoint: Option<String>,
+    pub(crate) service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            metrics_address: Some("0.0.0.0:9100".to_string()),
+            otlp_endpoint: None,
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+        }
+    }
+}
+
+/// Builds the tracer exporting the spans to the OTLP collector if configured.
+///
+/// The W3C trace context is propagated from the metadata of requests in any case.
+pub(crate) fn init_tracer(config: &TelemetryConfig) -> Result<Option<Tracer>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    Ok(Some(tracer))
+}
+
+/// Exports the spans not exported yet.
+pub(crate) fn shutdown_tracer() {
+    global::shutdown_tracer_provider();
+}
+
+/// Tower layer to trace calls under the context of the client and to count them in the metrics.
+#[derive(Clone, Default)]
+pub(crate) struct TelemetryLayer;
+
+impl<S> Layer<S> for TelemetryLayer {
+    type Service = Telemetry<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Telemetry { inner }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Telemetry<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for Telemetry<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let method = request.uri().path().to_string();
+
+        let span = tracing::info_span!("grpc", method = %method, otel.kind = "server");
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+        span.set_parent(parent);
+
+        let mut record = CallRecord {
+            method,
+            started_at: Instant::now(),
+            code: None,
+        };
+
+        // Take the service which has been driven to readiness
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(
+            async move {
+                let response = inner.call(request).await?;
+
+                // Errors are returned in the headers without a body
+                record.code = grpc_code(response.headers());
+
+                Ok(response.map(|body| {
+                    BoxBody::new(RecordedBody {
+                        inner: body,
+                        record,
+                    })
+                }))
+            }
+            .instrument(span),
+        )
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+fn grpc_code(headers: &HeaderMap) -> Option<Code> {
+    headers
+        .get("grpc-status")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i32>().ok())
+        .map(Code::from_i32)
+}
+
+/// Call counted in the metrics on drop, which is cancelled if the response has not ended.
+struct CallRecord {
+    method: String,
+    started_at: Instant,
+    code: Option<Code>,
+}
+
+impl Drop for CallRecord {
+    fn drop(&mut self) {
+        let code = format!("{:?}", self.code.unwrap_or(Code::Cancelled));
+        METRICS
+            .grpc_requests
+            .with_label_values(&[&self.method, &code])
+            .inc();
+        METRICS
+            .grpc_request_duration
+            .with_label_values(&[&self.method])
+            .observe(self.started_at.elapsed().as_secs_f64());
+    }
+}
+
+/// Response body taking the status of the call from the trailers.
+struct RecordedBody {
+    inner: BoxBody,
+    record: CallRecord,
+}
+
+impl Body for RecordedBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_trailers(cx);
+        if let Poll::Ready(Ok(Some(trailers))) = &poll {
+            if let Some(code) = grpc_code(trailers) {
+                this.record.code = Some(code);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
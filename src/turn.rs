@@ -0,0 +1,329 @@
+use crate::api_state::ApiState;
+use crate::chat_gpt_api::client::{ChoiceDelta, StreamedCompletion, Upstream};
+use crate::chat_gpt_api::memory::Memory;
+use crate::chat_gpt_api::specification::{Message, Options, Role};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Mutex, OwnedMutexGuard};
+use tonic::{Code, Status};
+
+/// Policy for a new message that arrives while a completion is in progress in the session.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BargeInPolicy {
+    /// Waits for the completion in progress to finish.
+    #[default]
+    Queue,
+    /// Rejects the new message with `Aborted`.
+    Reject,
+    /// Cancels the completion in progress and records only the part delivered to the client.
+    CancelAndReplace,
+}
+
+/// Exclusive turn of a session to run a completion.
+pub(crate) struct Turn {
+    session_id: String,
+    upstream: Arc<Upstream>,
+    cancel_rx: oneshot::Receiver<()>,
+    _guard: OwnedMutexGuard<()>,
+}
+
+pub(crate) enum Completion {
+    /// All choices have been streamed.
+    Finished(StreamedCompletion),
+    /// Cancelled by a new message with the parts of the choices delivered to the client.
+    Interrupted(Vec<String>),
+    /// Failed with the parts of the choices sent to the client before the error.
+    Failed(Vec<String>),
+}
+
+impl Completion {
+    /// Contents of the choices ordered by the index.
+    pub(crate) fn contents(&self) -> Vec<String> {
+        match self {
+            Completion::Finished(completion) => completion
+                .choices
+                .iter()
+                .map(|choice| choice.content.clone())
+                .collect(),
+            Completion::Interrupted(delivered) | Completion::Failed(delivered) => delivered.clone(),
+        }
+    }
+
+    /// Indexes and contents of the choices, without the ones not delivered at all.
+    pub(crate) fn choices(&self) -> Vec<(u32, String)> {
+        match self {
+            Completion::Finished(completion) => completion
+                .choices
+                .iter()
+                .map(|choice| (choice.index as u32, choice.content.clone()))
+                .collect(),
+            Completion::Interrupted(delivered) | Completion::Failed(delivered) => delivered
+                .iter()
+                .enumerate()
+                .filter(|(_, content)| !content.is_empty())
+                .map(|(index, content)| (index as u32, content.clone()))
+                .collect(),
+        }
+    }
+
+    /// The reply of the assistant to record in the context memory if a single choice is requested.
+    ///
+    /// Multiple choices are recorded when the client commits one of them.
+    pub(crate) fn reply(&self, n: u32) -> Option<String> {
+        if n > 1 {
+            return None;
+        }
+
+        match self.contents().as_slice() {
+            [content] if !content.is_empty() => Some(content.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Text of each choice of a streaming completion actually delivered to the client.
+#[derive(Clone, Default)]
+pub(crate) struct Delivery {
+    state: Arc<std::sync::Mutex<DeliveryState>>,
+}
+
+#[derive(Default)]
+struct DeliveryState {
+    texts: BTreeMap<u64, String>,
+    closed: bool,
+}
+
+impl Delivery {
+    /// Records the delta of the choice as delivered unless the delivery has been closed.
+    pub(crate) fn deliver(&self, index: u64, delta: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.closed {
+            state.texts.entry(index).or_default().push_str(delta);
+        }
+        !state.closed
+    }
+
+    /// Closes the delivery and returns the texts of the choices delivered so far.
+    pub(crate) fn close(&self) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+
+        let count = state.texts.keys().last().map_or(0, |index| index + 1);
+        (0..count)
+            .map(|index| state.texts.get(&index).cloned().unwrap_or_default())
+            .collect()
+    }
+}
+
+/// Begins a turn of the session following the barge-in policy.
+///
+/// With `CancelAndReplace`, the new message cancels the turn in progress or the one waiting for it,
+/// so that only the latest of rapid messages is completed.
+pub(crate) async fn begin_turn(
+    state: &Mutex<ApiState>,
+    session_id: &str,
+    policy: BargeInPolicy,
+) -> Result<Turn, Status> {
+    let (cancel_tx, mut cancel_rx) = oneshot::channel();
+    let (turn_lock, cancel_tx) = {
+        let mut state = state.lock().await;
+        let session = state.session(session_id);
+        let cancel_tx = if policy == BargeInPolicy::CancelAndReplace {
+            if let Some(cancel) = session.in_flight.replace(cancel_tx) {
+                let _ = cancel.send(());
+            }
+            None
+        } else {
+            Some(cancel_tx)
+        };
+        (Arc::clone(&session.turn_lock), cancel_tx)
+    };
+
+    let guard = match policy {
+        BargeInPolicy::Reject => turn_lock.try_lock_owned().map_err(|_| {
+            Status::new(
+                Code::Aborted,
+                "A completion is in progress in the session".to_string(),
+            )
+        })?,
+        BargeInPolicy::Queue => turn_lock.lock_owned().await,
+        // The waiting message is replaced by a newer one as well
+        BargeInPolicy::CancelAndReplace => tokio::select! {
+            guard = turn_lock.lock_owned() => guard,
+            _ = &mut cancel_rx => return Err(interrupted()),
+        },
+    };
+
+    let mut state = state.lock().await;
+    let session = state.session(session_id);
+    if let Some(cancel_tx) = cancel_tx {
+        session.in_flight = Some(cancel_tx);
+    }
+    // A new turn discards the choices not committed
+    session.pending_choices.clear();
+
+    Ok(Turn {
+        session_id: session_id.to_string(),
+        upstream: Arc::clone(&state.upstream),
+        cancel_rx,
+        _guard: guard,
+    })
+}
+
+/// Cancels the completion in progress in the session if any.
+pub(crate) async fn cancel_turn(state: &Mutex<ApiState>, session_id: &str) {
+    let mut state = state.lock().await;
+    if let Some(cancel) = state.session(session_id).in_flight.take() {
+        let _ = cancel.send(());
+    }
+}
+
+/// Checks whether a turn is in progress in the session.
+pub(crate) async fn is_busy(state: &Mutex<ApiState>, session_id: &str) -> bool {
+    let mut state = state.lock().await;
+    let is_locked = state.session(session_id).turn_lock.try_lock().is_err();
+    is_locked
+}
+
+pub(crate) fn interrupted() -> Status {
+    Status::new(Code::Cancelled, "Interrupted by a new message".to_string())
+}
+
+impl Turn {
+    /// Waits until the turn is cancelled by a new message.
+    pub(crate) async fn cancelled(&mut self) {
+        let _ = (&mut self.cancel_rx).await;
+    }
+
+    /// Runs a unary completion until it finishes, or None if the turn is cancelled by a new message.
+    pub(crate) async fn complete<T>(
+        &mut self,
+        completion: impl Future<Output = Result<T, Status>>,
+    ) -> Option<Result<T, Status>> {
+        tokio::select! {
+            result = completion => Some(result),
+            _ = self.cancelled() => None,
+        }
+    }
+
+    /// Streams the completion until it finishes or the turn is cancelled by a new message.
+    pub(crate) async fn stream(
+        &mut self,
+        tx: mpsc::UnboundedSender<anyhow::Result<ChoiceDelta>>,
+        options: Options,
+        delivery: &Delivery,
+    ) -> Completion {
+        let upstream = Arc::clone(&self.upstream);
+        tokio::select! {
+            result = upstream.complete_chat_stream(tx, options) => match result {
+                Ok(completion) => Completion::Finished(completion),
+                Err(_) => Completion::Failed(Vec::new()),
+            },
+            _ = self.cancelled() => Completion::Interrupted(delivery.close()),
+        }
+    }
+
+    /// Records the reply of the assistant and releases the turn.
+    pub(crate) fn finish(self, state: &mut ApiState, reply: Option<String>) {
+        let Turn {
+            session_id,
+            cancel_rx,
+            ..
+        } = self;
+        // The cancellation of a message waiting for the next turn is kept
+        drop(cancel_rx);
+        let session = state.session(&session_id);
+        if let Some(cancel) = &session.in_flight {
+            if cancel.is_closed() {
+                session.in_flight = None;
+            }
+        }
+
+        if let Some(reply) = reply {
+            session
+                .context_memory
+                .add(Message::new(Role::Assistant, reply));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn state() -> Mutex<ApiState> {
+        Mutex::new(ApiState::with_mock())
+    }
+
+    #[tokio::test]
+    async fn waiting_message_is_replaced_by_newer_one() {
+        let state = Arc::new(state());
+        let policy = BargeInPolicy::CancelAndReplace;
+        let mut first = begin_turn(&state, "session", policy).await.unwrap();
+
+        // The second message waits for the first turn to release the session
+        let second = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move { begin_turn(&state, "session", policy).await.map(|_| ()) }
+        });
+        first.cancelled().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let third = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move { begin_turn(&state, "session", policy).await.map(|_| ()) }
+        });
+        let status = tokio::time::timeout(Duration::from_secs(1), second)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Cancelled);
+
+        first.finish(&mut *state.lock().await, None);
+        third.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn finished_turn_keeps_cancellation_of_waiting_message() {
+        let state = Arc::new(state());
+        let policy = BargeInPolicy::CancelAndReplace;
+        let first = begin_turn(&state, "session", policy).await.unwrap();
+
+        let second = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move { begin_turn(&state, "session", policy).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        first.finish(&mut *state.lock().await, None);
+        let mut second = second.await.unwrap().unwrap();
+
+        // The turn of the second message is still cancellable
+        cancel_turn(&state, "session").await;
+        tokio::time::timeout(Duration::from_secs(1), second.cancelled())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn interrupted_choices_keep_their_indexes() {
+        // Only the second choice had been delivered
+        let completion = Completion::Interrupted(vec![String::new(), "Partial".to_string()]);
+
+        assert_eq!(completion.choices(), vec![(1, "Partial".to_string())]);
+        assert_eq!(completion.reply(2), None);
+    }
+
+    #[test]
+    fn only_single_choice_is_replied() {
+        let completion = Completion::Interrupted(vec!["Partial".to_string()]);
+
+        assert_eq!(completion.reply(1), Some("Partial".to_string()));
+        assert_eq!(completion.reply(2), None);
+        assert_eq!(Completion::Failed(Vec::new()).reply(1), None);
+    }
+}
@@ -0,0 +1,357 @@
+use crate::chat::my_chat::chat_rpc::chat_server::{Chat, ChatServer};
+use crate::chat::my_chat::chat_rpc::{ChatRequest, ChatStreamingResponse};
+use crate::chat::my_chat::MyChat;
+use crate::gateway::{reaction_json, to_json, Gateway, HTTP_STATUS_KEY};
+use crate::session::session_id_of;
+use crate::speak::my_speak::speak_rpc::speak_server::{Speak, SpeakServer};
+use crate::speak::my_speak::speak_rpc::speak_streaming_response::Content;
+use crate::speak::my_speak::speak_rpc::SpeakContent;
+use crate::speak::my_speak::MySpeak;
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{
+    HeaderMap, HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use hyper::{Body, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tonic::metadata::MetadataMap;
+use tonic::server::NamedService;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::{Code, Status};
+
+/// Message of the client in JSON like:
+///
+/// ```json
+/// { "type": "chat", "request_id": "1", "message": "Hello!", "n": 1 }
+/// { "type": "speak", "request_id": "2", "message": "おはよう!" }
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// The session of the request is ignored for the one of the connection.
+    Chat {
+        request_id: String,
+        #[serde(flatten)]
+        request: ChatRequest,
+    },
+    Speak {
+        request_id: String,
+        message: String,
+    },
+}
+
+/// Message of the server in JSON tagged by the request.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Chat {
+        request_id: String,
+        response: ChatStreamingResponse,
+    },
+    Reaction {
+        request_id: String,
+        reaction: serde_json::Value,
+    },
+    Delta {
+        request_id: String,
+        delta: String,
+    },
+    /// The request has finished.
+    Done {
+        request_id: String,
+    },
+    /// The request has failed, or the message of the client is invalid without the request ID.
+    Error {
+        request_id: Option<String>,
+        code: String,
+        message: String,
+    },
+}
+
+type ServerSender = mpsc::UnboundedSender<ServerMessage>;
+
+/// Version of the WebSocket protocol of RFC 6455.
+const VERSION: &str = "13";
+
+/// Upgrades the connection to a WebSocket bound to the session of the `session_id` query.
+///
+/// The client is authenticated and bound to the session on the upgrade,
+/// and each request is authenticated and rate limited as the corresponding gRPC method.
+/// Requests are run concurrently and the messages of them are multiplexed by the request ID.
+pub(crate) async fn upgrade(
+    gateway: Arc<Gateway>,
+    connect_info: TcpConnectInfo,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Status> {
+    let is_websocket = request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.eq_ignore_ascii_case("websocket"))
+        .is_some();
+    let key = match request.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) if is_websocket => key,
+        _ => {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Not a WebSocket request".to_string(),
+            ))
+        }
+    };
+    if request.headers().get(SEC_WEBSOCKET_VERSION) != Some(&HeaderValue::from_static(VERSION)) {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            HTTP_STATUS_KEY,
+            StatusCode::UPGRADE_REQUIRED.as_str().parse().unwrap(),
+        );
+        return Err(Status::with_metadata(
+            Code::InvalidArgument,
+            format!("Only version {} of WebSocket is supported", VERSION),
+            metadata,
+        ));
+    }
+    let accept = derive_accept_key(key.as_bytes());
+
+    let session_id = session_query(request.uri().query().unwrap_or_default());
+    let headers = request.headers().clone();
+
+    let identity = gateway.identify(
+        &[ChatServer::<MyChat>::NAME, SpeakServer::<MySpeak>::NAME],
+        connect_info.clone(),
+        headers.clone(),
+    )?;
+    let session_id = session_id_of(session_id, &identity);
+    gateway
+        .chat
+        .state
+        .lock()
+        .await
+        .authorize_session(&session_id, &identity)?;
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(request).await {
+            Err(error) => tracing::warn!(%error, "Failed to upgrade to WebSocket"),
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                let connection = Connection {
+                    gateway,
+                    connect_info,
+                    headers,
+                    session_id,
+                };
+                connection.serve(socket).await;
+            }
+        }
+    });
+
+    let mut response = hyper::Response::new(Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let response_headers = response.headers_mut();
+    response_headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    response_headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    response_headers.insert(SEC_WEBSOCKET_ACCEPT, accept.parse().unwrap());
+
+    Ok(response)
+}
+
+/// Decoded `session_id` of the query, empty if not set.
+fn session_query(query: &str) -> String {
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "session_id")
+        .map(|(_, value)| value.into_owned())
+        .unwrap_or_default()
+}
+
+#[derive(Clone)]
+struct Connection {
+    gateway: Arc<Gateway>,
+    connect_info: TcpConnectInfo,
+    /// Headers of the upgrade to authenticate each request.
+    headers: HeaderMap,
+    session_id: String,
+}
+
+impl Connection {
+    async fn serve<S>(self, socket: WebSocketStream<S>)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let (mut sink, mut source) = socket.split();
+        let (tx, mut rx) = mpsc::unbounded_channel::<ServerMessage>();
+        let mut shutdown = self.gateway.shutdown.clone();
+
+        // Closes the socket after the requests in progress have finished
+        let writer = tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if sink.send(Message::Text(to_json(&message))).await.is_err() {
+                    return;
+                }
+            }
+            let _ = sink.send(Message::Close(None)).await;
+        });
+
+        loop {
+            // No more requests are received on shutdown
+            let frame = tokio::select! {
+                frame = source.next() => frame,
+                _ = shutdown.stopped() => break,
+            };
+            let text = match frame {
+                Some(Ok(Message::Text(text))) => text,
+                None | Some(Ok(Message::Close(_))) | Some(Err(_)) => break,
+                // Pings are answered by the socket
+                Some(Ok(_)) => continue,
+            };
+
+            match serde_json::from_str::<ClientMessage>(&text) {
+                Err(error) => {
+                    let _ = tx.send(ServerMessage::Error {
+                        request_id: None,
+                        code: format!("{:?}", Code::InvalidArgument),
+                        message: format!("Invalid message: {}", error),
+                    });
+                }
+                Ok(message) => {
+                    tokio::spawn(self.clone().run(message, tx.clone()));
+                }
+            }
+        }
+
+        drop(tx);
+        let _ = writer.await;
+    }
+
+    async fn run(self, message: ClientMessage, tx: ServerSender) {
+        let (request_id, result) = match message {
+            ClientMessage::Chat {
+                request_id,
+                request,
+            } => {
+                let result = self.chat(&request_id, request, &tx).await;
+                (request_id, result)
+            }
+            ClientMessage::Speak {
+                request_id,
+                message,
+            } => {
+                let result = self.speak(&request_id, message, &tx).await;
+                (request_id, result)
+            }
+        };
+
+        let _ = tx.send(match result {
+            Ok(_) => ServerMessage::Done { request_id },
+            Err(status) => ServerMessage::Error {
+                request_id: Some(request_id),
+                code: format!("{:?}", status.code()),
+                message: status.message().to_string(),
+            },
+        });
+    }
+
+    async fn chat(
+        &self,
+        request_id: &str,
+        mut request: ChatRequest,
+        tx: &ServerSender,
+    ) -> Result<(), Status> {
+        request.session_id = self.session_id.clone();
+        let (request, _permit) = self.gateway.authorize(
+            ChatServer::<MyChat>::NAME,
+            "/chat.Chat/CompleteChatStreaming",
+            self.connect_info.clone(),
+            self.headers.clone(),
+            request,
+        )?;
+
+        let mut stream = self
+            .gateway
+            .chat
+            .complete_chat_streaming(request)
+            .await?
+            .into_inner();
+        while let Some(response) = stream.next().await {
+            send(
+                tx,
+                ServerMessage::Chat {
+                    request_id: request_id.to_string(),
+                    response: response?,
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    async fn speak(
+        &self,
+        request_id: &str,
+        message: String,
+        tx: &ServerSender,
+    ) -> Result<(), Status> {
+        let (request, _permit) = self.gateway.authorize(
+            SpeakServer::<MySpeak>::NAME,
+            "/speak.Speak/SpeakToStreaming",
+            self.connect_info.clone(),
+            self.headers.clone(),
+            SpeakContent {
+                message,
+                session_id: self.session_id.clone(),
+            },
+        )?;
+
+        let mut stream = self
+            .gateway
+            .speak
+            .speak_to_streaming(request)
+            .await?
+            .into_inner();
+        while let Some(response) = stream.next().await {
+            let request_id = request_id.to_string();
+            match response?.content {
+                None => {}
+                Some(Content::Reaction(reaction)) => send(
+                    tx,
+                    ServerMessage::Reaction {
+                        request_id,
+                        reaction: reaction_json(&reaction),
+                    },
+                )?,
+                Some(Content::Delta(delta)) => {
+                    send(tx, ServerMessage::Delta { request_id, delta })?
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn send(tx: &ServerSender, message: ServerMessage) -> Result<(), Status> {
+    tx.send(message)
+        .map_err(|_| Status::new(Code::Cancelled, "WebSocket was closed".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_of_the_query_is_decoded() {
+        assert_eq!(session_query("session_id=alice%20%E3%81%82"), "alice あ");
+        assert_eq!(session_query("lang=ja&session_id=a+b"), "a b");
+    }
+
+    #[test]
+    fn session_of_the_query_is_empty_if_not_set() {
+        assert_eq!(session_query(""), "");
+        assert_eq!(session_query("session=alice"), "");
+    }
+}
@@ -0,0 +1,128 @@
+mod common;
+
+use llm_agent_prototype_rust::rpc::admin::admin_client::AdminClient;
+use llm_agent_prototype_rust::rpc::admin::DumpMemoryRequest;
+use llm_agent_prototype_rust::rpc::chat::chat_client::ChatClient;
+use once_cell::sync::Lazy;
+use std::time::{Duration, Instant};
+use tonic::Code;
+
+static SERVER: Lazy<String> =
+    Lazy::new(|| common::start_with_fake(r#"barge_in = "cancel_and_replace""#));
+
+#[tokio::test]
+async fn new_message_replaces_unary_completion() {
+    let mut first_client = ChatClient::connect(SERVER.clone()).await.unwrap();
+    let mut second_client = first_client.clone();
+    let started_at = Instant::now();
+
+    let first = tokio::spawn(async move {
+        first_client
+            .complete_chat(common::chat_request("slow:First", "replace"))
+            .await
+    });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let second = second_client
+        .complete_chat(common::chat_request("Second", "replace"))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(second.response, "Echo: Second");
+    assert_eq!(first.await.unwrap().unwrap_err().code(), Code::Cancelled);
+    // Neither waited for the slow completion
+    assert!(started_at.elapsed() < Duration::from_secs(4));
+}
+
+#[tokio::test]
+async fn new_message_replaces_waiting_message() {
+    let mut client = ChatClient::connect(SERVER.clone()).await.unwrap();
+    let started_at = Instant::now();
+
+    let mut first_client = client.clone();
+    let first = tokio::spawn(async move {
+        first_client
+            .complete_chat(common::chat_request("slow:First", "replace-waiting"))
+            .await
+    });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let mut second_client = client.clone();
+    let second = tokio::spawn(async move {
+        second_client
+            .complete_chat(common::chat_request("slow:Second", "replace-waiting"))
+            .await
+    });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let third = client
+        .complete_chat(common::chat_request("Third", "replace-waiting"))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(third.response, "Echo: Third");
+    assert_eq!(first.await.unwrap().unwrap_err().code(), Code::Cancelled);
+    assert_eq!(second.await.unwrap().unwrap_err().code(), Code::Cancelled);
+    assert!(started_at.elapsed() < Duration::from_secs(4));
+}
+
+#[tokio::test]
+async fn new_message_keeps_delivered_part_of_stream() {
+    let mut client = ChatClient::connect(SERVER.clone()).await.unwrap();
+    let mut stream = client
+        .complete_chat_streaming(common::chat_request(
+            "drip:One two three four five six seven eight",
+            "replace-stream",
+        ))
+        .await
+        .unwrap()
+        .into_inner();
+
+    // Some of the deltas have been delivered
+    let mut delivered = String::new();
+    while !delivered.contains("One") {
+        delivered.push_str(&stream.message().await.unwrap().unwrap().delta);
+    }
+
+    let second = client
+        .complete_chat(common::chat_request("Second", "replace-stream"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(second.response, "Echo: Second");
+
+    let status = loop {
+        match stream.message().await {
+            Ok(Some(response)) => delivered.push_str(&response.delta),
+            Ok(None) => panic!("Stream finished without cancellation"),
+            Err(status) => break status,
+        }
+    };
+    assert_eq!(status.code(), Code::Cancelled);
+    assert!(!delivered.contains("eight"));
+
+    let mut admin = AdminClient::connect(SERVER.clone()).await.unwrap();
+    let memory = admin
+        .dump_memory(DumpMemoryRequest {
+            session_id: "replace-stream".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let messages: Vec<(&str, &str)> = memory
+        .messages
+        .iter()
+        .map(|message| (message.role.as_str(), message.content.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            ("user", "drip:One two three four five six seven eight"),
+            ("assistant", delivered.as_str()),
+            ("user", "Second"),
+            ("assistant", "Echo: Second"),
+        ]
+    );
+}
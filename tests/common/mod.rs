@@ -0,0 +1,188 @@
+// Each test uses only a part of the helpers
+#![allow(dead_code)]
+
+use futures_util::stream::{self, StreamExt};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use llm_agent_prototype_rust::rpc::chat::ChatRequest;
+use llm_agent_prototype_rust::{serve, ServerConfig};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Fake of the OpenAI API replying "Echo: <the last user message>",
+/// or failing with the status of a message like "status:500".
+/// A message like "slow:..." is replied after a few seconds,
+/// and the chunks of a streamed message like "drip:..." are sent one by one with a delay.
+///
+/// Function calls are answered with a fixed happy reaction.
+pub async fn fake_openai(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+    let options: Value = serde_json::from_slice(&body).unwrap();
+
+    let message = options["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|message| message["role"] == "user")
+        .and_then(|message| message["content"].as_str())
+        .unwrap_or_default()
+        .to_string();
+
+    if let Some(status) = message.strip_prefix("status:") {
+        let status = StatusCode::from_u16(status.parse().unwrap()).unwrap();
+        let error = json!({ "error": { "message": "Fake error", "type": "fake" } });
+        return Ok(Response::builder()
+            .status(status)
+            .body(Body::from(error.to_string()))
+            .unwrap());
+    }
+
+    if message.starts_with("slow:") {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+
+    let reply = format!("Echo: {}", message);
+
+    if options["stream"] == true {
+        let mut events = vec![json!({ "role": "assistant" })];
+        for word in reply.split_inclusive(' ') {
+            events.push(json!({ "content": word }));
+        }
+        // A chunk for each event
+        let mut lines: Vec<String> = events
+            .into_iter()
+            .map(|delta| format!("data: {}\n\n", chunk(delta, Value::Null)))
+            .collect();
+        lines.push(format!("data: {}\n\n", chunk(json!({}), json!("stop"))));
+        lines.push("data: [DONE]\n\n".to_string());
+        let delay = if message.starts_with("drip:") {
+            Duration::from_millis(200)
+        } else {
+            Duration::ZERO
+        };
+        let chunks = stream::iter(lines).then(move |line| async move {
+            tokio::time::sleep(delay).await;
+            Ok::<_, Infallible>(line)
+        });
+        return Ok(Response::new(Body::wrap_stream(chunks)));
+    }
+
+    let message = if options["function_call"].is_object() {
+        let arguments = json!({
+            "emotion": "EMOTION_HAPPY",
+            "motion": "MOTION_HAPPY",
+            "cry": "CRY_HAPPY",
+        });
+        json!({
+            "role": "assistant",
+            "function_call": { "name": "reaction_generator", "arguments": arguments.to_string() },
+        })
+    } else {
+        json!({ "role": "assistant", "content": reply })
+    };
+
+    let completion = json!({
+        "id": "chatcmpl-fake",
+        "object": "chat.completion",
+        "created": 0,
+        "model": options["model"],
+        "choices": [{ "index": 0, "message": message, "finish_reason": "stop" }],
+        "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 },
+    });
+
+    Ok(Response::new(Body::from(completion.to_string())))
+}
+
+fn chunk(delta: Value, finish_reason: Value) -> Value {
+    json!({
+        "id": "chatcmpl-fake",
+        "object": "chat.completion.chunk",
+        "created": 0,
+        "model": "gpt-3.5-turbo-0613",
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+    })
+}
+
+/// Starts the server in plaintext and returns the address, where the top-level settings
+/// and the `[upstream]` section with the address of the fake OpenAI API are given.
+///
+/// The server runs on its own runtime to outlive the runtimes of the tests.
+pub fn start_server(settings: &str, upstream: fn(SocketAddr) -> String) -> String {
+    let (tx, rx) = mpsc::channel();
+    let settings = settings.to_string();
+
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let fake = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service_fn(
+                |_| async { Ok::<_, Infallible>(service_fn(fake_openai)) },
+            ));
+            let config: ServerConfig = toml::from_str(&format!(
+                r#"
+                tls = false
+                accounting_log_interval_secs = 0
+                upstream_check_interval_secs = 0
+                {}
+
+                [auth]
+                anonymous_role = "admin"
+
+                [telemetry]
+                metrics_address = "127.0.0.1:0"
+
+                [upstream]
+                api_key = "sk-fake"
+                {}
+                "#,
+                settings,
+                upstream(fake.local_addr())
+            ))
+            .unwrap();
+            tokio::spawn(fake);
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            tx.send(format!("http://{}", listener.local_addr().unwrap()))
+                .unwrap();
+            serve(config, listener, std::future::pending())
+                .await
+                .unwrap();
+        });
+    });
+
+    rx.recv().unwrap()
+}
+
+/// Starts the server with the fake OpenAI API as the upstream.
+pub fn start_with_fake(settings: &str) -> String {
+    start_server(settings, |fake| {
+        format!(r#"base_url = "http://{}/v1""#, fake)
+    })
+}
+
+/// Starts the server with the mock streaming its replies without a delay as the upstream.
+pub fn start_with_mock(settings: &str) -> String {
+    start_server(settings, |_| {
+        r#"
+        [upstream.mock]
+        chunk_delay_millis = 0
+        "#
+        .to_string()
+    })
+}
+
+/// Request of a single completion of the message in the session.
+pub fn chat_request(message: &str, session_id: &str) -> ChatRequest {
+    ChatRequest {
+        message: message.to_string(),
+        session_id: session_id.to_string(),
+        n: 1,
+        continue_on_length: false,
+        max_tokens: 0,
+    }
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use llm_agent_prototype_rust::rpc::admin::admin_client::AdminClient;
+use llm_agent_prototype_rust::rpc::admin::DumpMemoryRequest;
+use llm_agent_prototype_rust::rpc::chat::chat_client::ChatClient;
+use llm_agent_prototype_rust::rpc::chat::ChatRequest;
+use once_cell::sync::Lazy;
+use tonic::Code;
+
+const REPLY: &str = "The quick brown fox jumps over the lazy dog.";
+
+/// Server with the mock cutting off the reply by `max_tokens`.
+static SERVER: Lazy<String> = Lazy::new(|| {
+    common::start_server("", |_| {
+        format!(
+            r#"
+            [upstream.mock]
+            replies = ["{}"]
+            chunk_delay_millis = 0
+            "#,
+            REPLY
+        )
+    })
+});
+
+/// Server with the mock failing every second request, which is the first continuation.
+static FAILING_SERVER: Lazy<String> = Lazy::new(|| {
+    common::start_server("", |_| {
+        format!(
+            r#"
+            [upstream.mock]
+            replies = ["{}"]
+            chunk_delay_millis = 0
+            error = "server_error"
+            error_interval = 2
+            "#,
+            REPLY
+        )
+    })
+});
+
+fn chat_request(session_id: &str, continue_on_length: bool) -> ChatRequest {
+    ChatRequest {
+        continue_on_length,
+        // 12 ASCII characters for each completion
+        max_tokens: 3,
+        ..common::chat_request("Tell me a pangram", session_id)
+    }
+}
+
+#[tokio::test]
+async fn answer_is_cut_off_by_max_tokens() {
+    let mut client = ChatClient::connect(SERVER.clone()).await.unwrap();
+
+    let response = client
+        .complete_chat(chat_request("cut_off", false))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(response.response, "The quick br");
+    assert_eq!(response.finish_reason, "length");
+}
+
+#[tokio::test]
+async fn answer_is_continued_on_length() {
+    let mut client = ChatClient::connect(SERVER.clone()).await.unwrap();
+
+    let response = client
+        .complete_chat(chat_request("continued", true))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(response.response, REPLY);
+    assert_eq!(response.finish_reason, "stop");
+}
+
+#[tokio::test]
+async fn streamed_answer_is_continued_on_length() {
+    let mut client = ChatClient::connect(SERVER.clone()).await.unwrap();
+
+    let mut stream = client
+        .complete_chat_streaming(chat_request("streamed", true))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut content = String::new();
+    let mut summary = None;
+    while let Some(response) = stream.message().await.unwrap() {
+        // The pieces cut off are not finished for the client
+        assert_ne!(response.finish_reason, "length");
+        content.push_str(&response.delta);
+        if response.summary.is_some() {
+            summary = response.summary;
+        }
+    }
+
+    assert_eq!(content, REPLY);
+    assert!(!summary.unwrap().truncated);
+}
+
+#[tokio::test]
+async fn sent_answer_is_kept_when_continuation_fails() {
+    let mut client = ChatClient::connect(FAILING_SERVER.clone()).await.unwrap();
+
+    let mut stream = client
+        .complete_chat_streaming(chat_request("failed", true))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut content = String::new();
+    let status = loop {
+        match stream.message().await {
+            Ok(Some(response)) => content.push_str(&response.delta),
+            Ok(None) => panic!("Stream finished without the error"),
+            Err(status) => break status,
+        }
+    };
+    assert_eq!(status.code(), Code::Internal);
+    assert_eq!(content, "The quick br");
+
+    let mut admin = AdminClient::connect(FAILING_SERVER.clone()).await.unwrap();
+    let memory = admin
+        .dump_memory(DumpMemoryRequest {
+            session_id: "failed".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let messages: Vec<(&str, &str)> = memory
+        .messages
+        .iter()
+        .map(|message| (message.role.as_str(), message.content.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![("user", "Tell me a pangram"), ("assistant", "The quick br"),]
+    );
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use futures_util::{SinkExt, StreamExt};
+use hyper::{Body, Client, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::net::TcpListener;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+/// Base URL of the gateway on a free port with the mock echoing the messages.
+static GATEWAY: Lazy<String> = Lazy::new(|| {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    common::start_with_mock(&format!(
+        r#"
+[gateway]
+address = "{}"
+max_body_bytes = 1024
+"#,
+        address
+    ));
+    format!("http://{}", address)
+});
+
+/// Posts the body to the path, retrying until the gateway has started.
+async fn post_raw(path: &str, body: String) -> Response<Body> {
+    let client = Client::new();
+    for _ in 0..50 {
+        let request = Request::post(format!("{}{}", *GATEWAY, path))
+            .header("content-type", "application/json")
+            .body(Body::from(body.clone()))
+            .unwrap();
+        match client.request(request).await {
+            Ok(response) => return response,
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+
+    panic!("Gateway has not started");
+}
+
+/// Posts the body to the path and parses the response as JSON.
+async fn post(path: &str, body: String) -> (StatusCode, Value) {
+    let response = post_raw(path, body).await;
+    let status = response.status();
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+/// Opens a WebSocket to the gateway, retrying until the gateway has started.
+async fn connect_websocket(query: &str) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
+    let url = format!("{}/v1/ws?{}", GATEWAY.replace("http://", "ws://"), query);
+    for _ in 0..50 {
+        match connect_async(&url).await {
+            Ok((socket, _)) => return socket,
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+
+    panic!("Gateway has not started");
+}
+
+#[tokio::test]
+async fn chat_is_answered_as_json() {
+    let body = json!({ "message": "Hello", "session_id": "gateway" });
+    let (status, response) = post("/v1/chat", body.to_string()).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(response["response"], "Hello");
+}
+
+#[tokio::test]
+async fn chat_completions_are_answered_in_the_openai_format() {
+    let body = json!({
+        "model": "gpt-3.5-turbo",
+        "messages": [{ "role": "user", "content": "Hi" }],
+    });
+    let (status, response) = post("/v1/chat/completions", body.to_string()).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(response["choices"][0]["message"]["content"], "Hi");
+}
+
+#[tokio::test]
+async fn chat_completions_are_streamed_as_server_sent_events() {
+    let body = json!({
+        "model": "gpt-3.5-turbo",
+        "messages": [{ "role": "user", "content": "Hi there" }],
+        "stream": true,
+        "user": "gateway-stream",
+    });
+    let response = post_raw("/v1/chat/completions", body.to_string()).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let text = String::from_utf8(bytes.to_vec()).unwrap();
+    let events: Vec<&str> = text
+        .split("\n\n")
+        .filter(|event| !event.is_empty())
+        .map(|event| event.strip_prefix("data: ").unwrap())
+        .collect();
+
+    assert_eq!(events.last(), Some(&"[DONE]"));
+    let chunks: Vec<Value> = events[..events.len() - 1]
+        .iter()
+        .map(|event| serde_json::from_str(event).unwrap())
+        .collect();
+    assert!(chunks
+        .iter()
+        .all(|chunk| chunk["object"] == "chat.completion.chunk"));
+    // The role is sent only in the first chunk
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    assert!(chunks[1..]
+        .iter()
+        .all(|chunk| chunk["choices"][0]["delta"]["role"].is_null()));
+
+    let content: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert_eq!(content, "Hi there");
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+}
+
+#[tokio::test]
+async fn invalid_json_is_a_bad_request() {
+    let (status, response) = post("/v1/chat", "{".to_string()).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(response["code"], "InvalidArgument");
+}
+
+#[tokio::test]
+async fn large_body_is_rejected() {
+    let body = json!({ "message": "a".repeat(2048), "session_id": "gateway-large" });
+    let (status, _) = post("/v1/chat", body.to_string()).await;
+
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn unknown_route_is_not_found() {
+    let (status, _) = post("/v1/unknown", "{}".to_string()).await;
+
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn chat_is_streamed_over_websocket() {
+    let mut socket = connect_websocket("session_id=web%20socket").await;
+    let message = json!({ "type": "chat", "request_id": "1", "message": "Hello there" });
+    socket
+        .send(Message::Text(message.to_string()))
+        .await
+        .unwrap();
+
+    let mut content = String::new();
+    loop {
+        let Some(Ok(Message::Text(text))) = socket.next().await else {
+            panic!("WebSocket was closed before the request finished");
+        };
+        let message: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(message["request_id"], "1");
+        match message["type"].as_str().unwrap() {
+            "chat" => content.push_str(message["response"]["delta"].as_str().unwrap()),
+            "done" => break,
+            other => panic!("Unexpected message {}: {}", other, text),
+        }
+    }
+
+    assert_eq!(content, "Hello there");
+}
+
+#[tokio::test]
+async fn unsupported_websocket_version_is_rejected() {
+    // Waits for the gateway
+    connect_websocket("").await;
+
+    let request = Request::get(format!("{}/v1/ws", *GATEWAY))
+        .header("connection", "Upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .header("sec-websocket-version", "8")
+        .body(Body::empty())
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+}
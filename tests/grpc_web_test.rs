@@ -0,0 +1,66 @@
+mod common;
+
+use hyper::{Body, Client, Request, StatusCode};
+use llm_agent_prototype_rust::rpc::chat::ChatResponse;
+use once_cell::sync::Lazy;
+use prost::Message;
+
+const ORIGIN: &str = "https://viewer.example.com";
+
+static SERVER: Lazy<String> = Lazy::new(|| {
+    common::start_with_fake(&format!(
+        r#"
+[grpc_web]
+allowed_origins = ["{}"]
+"#,
+        ORIGIN
+    ))
+});
+
+/// Frames of gRPC-Web, which are a flag, the length and the payload.
+fn frames(mut bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut frames = Vec::new();
+    while !bytes.is_empty() {
+        let length = u32::from_be_bytes(bytes[1..5].try_into().unwrap()) as usize;
+        frames.push((bytes[0], bytes[5..5 + length].to_vec()));
+        bytes = &bytes[5 + length..];
+    }
+    frames
+}
+
+#[tokio::test]
+async fn unary_call_is_answered_over_http1() {
+    let message = common::chat_request("Hello", "grpc_web").encode_to_vec();
+    let mut body = vec![0];
+    body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    body.extend_from_slice(&message);
+
+    let request = Request::post(format!("{}/chat.Chat/CompleteChat", *SERVER))
+        .header("content-type", "application/grpc-web+proto")
+        .header("x-grpc-web", "1")
+        .header("origin", ORIGIN)
+        .body(Body::from(body))
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["access-control-allow-origin"], ORIGIN);
+    assert!(response.headers()["access-control-expose-headers"]
+        .to_str()
+        .unwrap()
+        .contains("grpc-status"));
+
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let frames = frames(&bytes);
+    assert_eq!(frames.len(), 2);
+
+    let (flag, message) = &frames[0];
+    assert_eq!(*flag, 0);
+    let message = ChatResponse::decode(message.as_slice()).unwrap();
+    assert_eq!(message.response, "Echo: Hello");
+
+    // Trailers are sent in the body
+    let (flag, trailers) = &frames[1];
+    assert_eq!(*flag, 0x80);
+    assert!(String::from_utf8_lossy(trailers).contains("grpc-status:0"));
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use llm_agent_prototype_rust::rpc::chat::chat_client::ChatClient;
+use once_cell::sync::Lazy;
+use tonic::Code;
+
+/// Server replaying the cassettes recorded against the fake OpenAI API like:
+///
+/// ```toml
+/// [upstream.cassettes]
+/// mode = "record"
+/// directory = "tests/cassettes"
+/// ```
+static SERVER: Lazy<String> = Lazy::new(|| {
+    common::start_server("", |_| {
+        r#"
+        base_url = "http://127.0.0.1:9/v1"
+
+        [upstream.cassettes]
+        mode = "replay"
+        directory = "tests/cassettes"
+        realtime = false
+        "#
+        .to_string()
+    })
+});
+
+#[tokio::test]
+async fn recorded_conversation_is_replayed() {
+    let mut client = ChatClient::connect(SERVER.clone()).await.unwrap();
+
+    let response = client
+        .complete_chat(common::chat_request("Hello", "replay"))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.response, "Echo: Hello");
+
+    // The second request is matched with the system prompt and the history of the first one
+    let mut stream = client
+        .complete_chat_streaming(common::chat_request("Tell me more", "replay"))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut deltas = Vec::new();
+    while let Some(response) = stream.message().await.unwrap() {
+        if response.summary.is_none() {
+            deltas.push(response.delta);
+        }
+    }
+    assert_eq!(deltas, vec!["Echo: ", "Tell ", "me ", "more", ""]);
+}
+
+#[tokio::test]
+async fn unrecorded_request_fails() {
+    let mut client = ChatClient::connect(SERVER.clone()).await.unwrap();
+
+    let status = client
+        .complete_chat(common::chat_request("Not recorded", "unrecorded"))
+        .await
+        .unwrap_err();
+
+    assert_eq!(status.code(), Code::Internal);
+    assert!(status.message().contains("No cassette"));
+}
@@ -0,0 +1,266 @@
+mod common;
+
+use llm_agent_prototype_rust::rpc::admin::admin_client::AdminClient;
+use llm_agent_prototype_rust::rpc::admin::DumpMemoryRequest;
+use llm_agent_prototype_rust::rpc::chat::chat_client::ChatClient;
+use llm_agent_prototype_rust::rpc::speak::converse_request::Action;
+use llm_agent_prototype_rust::rpc::speak::converse_response::Event;
+use llm_agent_prototype_rust::rpc::speak::speak_client::SpeakClient;
+use llm_agent_prototype_rust::rpc::speak::{
+    ConverseRequest, Cry, Emotion, Motion, Reset, SpeakContent,
+};
+use once_cell::sync::Lazy;
+use std::time::{Duration, Instant};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::Code;
+
+static SERVER: Lazy<String> = Lazy::new(|| common::start_with_fake(""));
+
+#[tokio::test]
+async fn complete_chat_replies_from_upstream() {
+    let mut client = ChatClient::connect(SERVER.clone()).await.unwrap();
+
+    let response = client
+        .complete_chat(common::chat_request("Hello", "complete_chat"))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(response.response, "Echo: Hello");
+    assert_eq!(response.finish_reason, "stop");
+    assert_eq!(response.usage.unwrap().total_tokens, 15);
+}
+
+#[tokio::test]
+async fn complete_chat_streaming_joins_deltas() {
+    let mut client = ChatClient::connect(SERVER.clone()).await.unwrap();
+
+    let mut stream = client
+        .complete_chat_streaming(common::chat_request("How are you?", "streaming"))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut content = String::new();
+    let mut summary = None;
+    while let Some(response) = stream.message().await.unwrap() {
+        content.push_str(&response.delta);
+        if response.summary.is_some() {
+            summary = response.summary;
+        }
+    }
+
+    assert_eq!(content, "Echo: How are you?");
+    let summary = summary.unwrap();
+    assert_eq!(summary.choices[0].finish_reason, "stop");
+    assert!(!summary.truncated);
+}
+
+#[tokio::test]
+async fn speak_to_reacts_by_function_call() {
+    let mut client = SpeakClient::connect(SERVER.clone()).await.unwrap();
+
+    let reaction = client
+        .speak_to(SpeakContent {
+            message: "Nice to meet you".to_string(),
+            session_id: "speak_to".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(reaction.emotion(), Emotion::Happy);
+    assert_eq!(reaction.motion(), Motion::Happy);
+    assert_eq!(reaction.cry(), Cry::Happy);
+}
+
+#[tokio::test]
+async fn chat_is_recorded_in_memory() {
+    let mut chat = ChatClient::connect(SERVER.clone()).await.unwrap();
+    let mut admin = AdminClient::connect(SERVER.clone()).await.unwrap();
+
+    chat.complete_chat(common::chat_request("First", "memory"))
+        .await
+        .unwrap();
+    chat.complete_chat(common::chat_request("Second", "memory"))
+        .await
+        .unwrap();
+
+    let memory = admin
+        .dump_memory(DumpMemoryRequest {
+            session_id: "memory".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let contents: Vec<(&str, &str)> = memory
+        .messages
+        .iter()
+        .map(|message| (message.role.as_str(), message.content.as_str()))
+        .collect();
+
+    assert_eq!(
+        contents,
+        vec![
+            ("user", "First"),
+            ("assistant", "Echo: First"),
+            ("user", "Second"),
+            ("assistant", "Echo: Second"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn upstream_error_fails_the_call() {
+    let mut client = ChatClient::connect(SERVER.clone()).await.unwrap();
+
+    let status = client
+        .complete_chat(common::chat_request("status:500", "upstream_error"))
+        .await
+        .unwrap_err();
+
+    assert_eq!(status.code(), Code::Internal);
+    assert!(status.message().contains("500"));
+}
+
+#[tokio::test]
+async fn converse_continues_after_failed_commands() {
+    let mut client = SpeakClient::connect(SERVER.clone()).await.unwrap();
+
+    let requests = [
+        Action::SetPersona("nobody".to_string()),
+        Action::Utterance("status:500".to_string()),
+        Action::Utterance("Hi".to_string()),
+    ]
+    .into_iter()
+    .map(|action| ConverseRequest {
+        session_id: "converse".to_string(),
+        action: Some(action),
+    })
+    .collect::<Vec<_>>();
+
+    let mut stream = client
+        .converse(tokio_stream::iter(requests))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut events = Vec::new();
+    while let Some(response) = stream.message().await.unwrap() {
+        events.push(response.event.unwrap());
+    }
+
+    assert!(matches!(
+        &events[0],
+        Event::Failed(failed) if failed.turn_id == 0 && failed.code == Code::NotFound as i32
+    ));
+    assert!(matches!(&events[1], Event::TurnStarted(started) if started.turn_id == 1));
+    assert!(matches!(&events[2], Event::Failed(failed) if failed.turn_id == 1));
+    assert!(matches!(&events[3], Event::TurnStarted(started) if started.turn_id == 2));
+    assert!(matches!(&events[4], Event::Reaction(_)));
+    assert!(matches!(
+        events.last().unwrap(),
+        Event::TurnFinished(finished) if finished.turn_id == 2 && !finished.interrupted
+    ));
+}
+
+#[tokio::test]
+async fn converse_resets_at_once() {
+    let mut client = SpeakClient::connect(SERVER.clone()).await.unwrap();
+    let request = |action| ConverseRequest {
+        session_id: "converse_reset".to_string(),
+        action: Some(action),
+    };
+    let (request_tx, request_rx) = tokio::sync::mpsc::unbounded_channel();
+    request_tx
+        .send(request(Action::Utterance("slow:First".to_string())))
+        .unwrap();
+    request_tx
+        .send(request(Action::Utterance("Queued".to_string())))
+        .unwrap();
+
+    let started_at = Instant::now();
+    let mut stream = client
+        .converse(UnboundedReceiverStream::new(request_rx))
+        .await
+        .unwrap()
+        .into_inner();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    request_tx.send(request(Action::Reset(Reset {}))).unwrap();
+    request_tx
+        .send(request(Action::Utterance("After".to_string())))
+        .unwrap();
+    drop(request_tx);
+
+    let mut events = Vec::new();
+    while let Some(response) = stream.message().await.unwrap() {
+        events.push(response.event.unwrap());
+    }
+
+    // The slow turn is cancelled and the queued utterance is dropped
+    assert!(started_at.elapsed() < Duration::from_secs(5));
+    assert!(matches!(&events[0], Event::TurnStarted(started) if started.turn_id == 1));
+    assert!(matches!(
+        &events[1],
+        Event::TurnFinished(finished) if finished.turn_id == 1 && finished.interrupted
+    ));
+    assert!(matches!(&events[2], Event::TurnStarted(started) if started.turn_id == 2));
+    assert!(matches!(
+        events.last().unwrap(),
+        Event::TurnFinished(finished) if finished.turn_id == 2 && !finished.interrupted
+    ));
+
+    let mut admin = AdminClient::connect(SERVER.clone()).await.unwrap();
+    let memory = admin
+        .dump_memory(DumpMemoryRequest {
+            session_id: "converse_reset".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let messages: Vec<(&str, &str)> = memory
+        .messages
+        .iter()
+        .map(|message| (message.role.as_str(), message.content.as_str()))
+        .collect();
+    // The reaction is recorded as a function call without content
+    assert_eq!(
+        messages,
+        vec![
+            ("user", "After"),
+            ("assistant", ""),
+            ("assistant", "Echo: After")
+        ]
+    );
+}
+
+#[tokio::test]
+async fn converse_rejects_another_session() {
+    let mut client = SpeakClient::connect(SERVER.clone()).await.unwrap();
+
+    let requests = vec![
+        ConverseRequest {
+            session_id: "converse_bound".to_string(),
+            action: None,
+        },
+        ConverseRequest {
+            session_id: "converse_other".to_string(),
+            action: Some(Action::Utterance("Hi".to_string())),
+        },
+    ];
+
+    let mut stream = client
+        .converse(tokio_stream::iter(requests))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut events = Vec::new();
+    while let Some(response) = stream.message().await.unwrap() {
+        events.push(response.event.unwrap());
+    }
+
+    assert_eq!(events.len(), 1);
+    assert!(matches!(
+        &events[0],
+        Event::Failed(failed) if failed.turn_id == 0 && failed.code == Code::InvalidArgument as i32
+    ));
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use llm_agent_prototype_rust::rpc::chat::chat_client::ChatClient;
+use llm_agent_prototype_rust::rpc::chat::ChoiceSelection;
+use llm_agent_prototype_rust::rpc::speak::speak_client::SpeakClient;
+use llm_agent_prototype_rust::rpc::speak::EmotionStateRequest;
+use once_cell::sync::Lazy;
+use tonic::{Code, Request};
+
+static SERVER: Lazy<String> = Lazy::new(|| {
+    common::start_with_mock(
+        r#"
+[[auth.keys]]
+identity = "alice"
+sha256 = "72ee9d4355ccb9d3a4c9dbf37382e38e75c1b1a225b5bd1f729ee91bbda30c20"
+
+[[auth.keys]]
+identity = "bob"
+sha256 = "9b94dc1a51a38769f135edf04033ad7f2f487b6c25929be7a861cfc1ab10cf98"
+
+[[auth.keys]]
+identity = "carol"
+sha256 = "368c3387fc9b5ce6ab156ad952031f52bc9154e89a727020cd314f8910a21823"
+role = "operator"
+"#,
+    )
+});
+
+fn with_key<T>(message: T, key: &str) -> Request<T> {
+    let mut request = Request::new(message);
+    request
+        .metadata_mut()
+        .insert("authorization", format!("Bearer {}", key).parse().unwrap());
+    request
+}
+
+#[tokio::test]
+async fn sessions_are_bound_to_the_owner() {
+    let mut chat = ChatClient::connect(SERVER.clone()).await.unwrap();
+    let mut speak = SpeakClient::connect(SERVER.clone()).await.unwrap();
+
+    chat.complete_chat(with_key(
+        common::chat_request("Hello", "owned"),
+        "alice-key",
+    ))
+    .await
+    .unwrap();
+
+    let status = chat
+        .complete_chat(with_key(common::chat_request("Hi", "owned"), "bob-key"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+
+    let selection = ChoiceSelection {
+        session_id: "owned".to_string(),
+        index: 0,
+    };
+    let status = chat
+        .commit_choice(with_key(selection, "bob-key"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+
+    let emotion_request = || EmotionStateRequest {
+        session_id: "owned".to_string(),
+    };
+    let status = speak
+        .get_emotion_state(with_key(emotion_request(), "bob-key"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+
+    // Operators can access the sessions of others
+    speak
+        .get_emotion_state(with_key(emotion_request(), "carol-key"))
+        .await
+        .unwrap();
+    speak
+        .get_emotion_state(with_key(emotion_request(), "alice-key"))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn clients_without_session_id_have_their_own_sessions() {
+    let mut chat = ChatClient::connect(SERVER.clone()).await.unwrap();
+    let mut speak = SpeakClient::connect(SERVER.clone()).await.unwrap();
+
+    chat.complete_chat(with_key(common::chat_request("Hello", ""), "alice-key"))
+        .await
+        .unwrap();
+    chat.complete_chat(with_key(common::chat_request("Hi", ""), "bob-key"))
+        .await
+        .unwrap();
+
+    // The default session of each client is bound to the client
+    for key in ["alice-key", "bob-key"] {
+        speak
+            .get_emotion_state(with_key(
+                EmotionStateRequest {
+                    session_id: String::new(),
+                },
+                key,
+            ))
+            .await
+            .unwrap();
+    }
+    let status = speak
+        .get_emotion_state(with_key(
+            EmotionStateRequest {
+                session_id: "default:alice".to_string(),
+            },
+            "bob-key",
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+}